@@ -1,33 +1,320 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
 
-pub const POSE1: Transform = Transform {
-    rotation: Quat::from_xyzw(0.0, 0.0, 0.0, 1.0),
-    translation: Vec3::new(0.0, 0.0, 0.0),
-    scale: Vec3::new(1.0, 1.0, 1.0)
-};
-
-// True POSE2
-pub const CORRECT_POSE2: Transform = Transform {
-    rotation: Quat::from_xyzw(0.0059421, -0.0373319, 0.0209614, 0.999065),
-    translation: Vec3::new(0.067494*10.0, 0.058187*10.0, 0.0369303*10.0),
-    scale: Vec3::new(1.0, 1.0, 1.0)
-};
-
-// Initial POSE2 (It's actually the POSE4)
-pub const POSE2: Transform = Transform {
-    rotation: Quat::from_xyzw(0.0610943, -0.324556, 0.149797, 0.931926),
-    translation: Vec3::new(0.649504*10.0, 0.394082*10.0, 0.590801*10.0),
-    scale: Vec3::new(1.0, 1.0, 1.0)
-};
-
-// Initial POSE2 (Harder starting pose for ICP)
-/*pub const POSE2: Transform = Transform {
-    rotation: Quat::from_xyzw(-0.995, -0.031, 0.015, 0.090),
-    translation: Vec3::new(-0.1005*10.0, 0.4441*10.0, 0.6408*10.0),
-    scale: Vec3::new(1.0, 1.0, 1.0)
-};*/
-/*pub const POSE2: Transform = Transform {
-    rotation: Quat::from_xyzw(0.0, 0.0, 0.0, 1.0),
-    translation: Vec3::new(0.0, 0.0, 0.0),
-    scale: Vec3::new(1.0, 1.0, 1.0)
-};*/
\ No newline at end of file
+use crate::dataset::{FrameLabels, SceneLabels, Trajectory};
+use crate::error::RegistrationError;
+use crate::robust::PointLabels;
+use crate::solvers::pose;
+use crate::solvers::{InitialGuess, Solver};
+
+// Experiment loaded when no file is given on the command line
+pub const DEFAULT_EXPERIMENT_PATH: &str = "experiments/default.toml";
+
+/// Rigid pose as written in the experiment file
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Pose {
+    pub translation: [f32; 3],
+    pub rotation: [f32; 4], // Quaternion as [x, y, z, w]
+}
+
+impl Default for Pose {
+    fn default() -> Self {
+        Pose::from(Transform::IDENTITY)
+    }
+}
+
+impl From<Pose> for Transform {
+    fn from(pose: Pose) -> Self {
+        let [x, y, z, w] = pose.rotation;
+        Transform {
+            rotation: Quat::from_xyzw(x, y, z, w).normalize(),
+            translation: Vec3::from(pose.translation),
+            scale: Vec3::ONE
+        }
+    }
+}
+
+impl From<Transform> for Pose {
+    fn from(transform: Transform) -> Self {
+        Pose {
+            translation: transform.translation.to_array(),
+            rotation: transform.rotation.to_array()
+        }
+    }
+}
+
+/// Largest errors with respect to the ground truth for which a registration counts as successful
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SuccessThresholds {
+    pub rotation: f32,    // Degrees
+    pub translation: f32, // Meters
+}
+
+impl Default for SuccessThresholds {
+    fn default() -> Self {
+        SuccessThresholds { rotation: 5.0, translation: 0.1 }
+    }
+}
+
+/// Error of an estimated pose with respect to the ground truth
+#[derive(Debug, Clone, Copy)]
+pub struct PoseError {
+    pub rotation: f32,    // Geodesic angle, in degrees
+    pub translation: f32, // Meters
+    pub success: bool,    // Both errors are within the success thresholds
+}
+
+/// RGB-D image pair
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Frame {
+    pub color: PathBuf,
+    pub depth: PathBuf,
+}
+
+/// Frames of a scene pose file the starting pose and the ground truth are computed from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoseFile {
+    pub path: PathBuf,                // `scene-XX.pose` file of the dataset
+    pub source_frame: usize,          // Frame number of the frame to align
+    pub target_frame: usize,          // Frame number of the reference frame
+    pub initial_frame: Option<usize>, // Frame whose pose is used as the starting pose, instead of initial_pose
+}
+
+/// Object classes of the points, transferred from the labelled point cloud of the scene
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LabelOptions {
+    pub path: PathBuf,      // `scene-XX.label` file of the dataset
+    pub cloud: PathBuf,     // `scene-XX.ply` file, whose points the labels belong to
+    #[serde(default = "default_label_distance")]
+    pub max_distance: f32,  // Meters, points farther from the labelled cloud are unlabelled
+    #[serde(default)]
+    pub excluded: Vec<u32>, // Classes removed from the points before the registration
+    #[serde(default)]
+    pub colors: bool,       // Color the points by class in the viewer
+}
+
+fn default_label_distance() -> f32 {
+    0.02
+}
+
+/// Solver of an experiment, with the label of its rows in the benchmark
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SolverEntry {
+    #[serde(flatten)]
+    pub solver: Solver,
+    pub label: Option<String>, // Distinguishes entries of the same solver, its name when not given
+}
+
+impl SolverEntry {
+    pub fn label(&self) -> &str {
+        self.label.as_deref().unwrap_or_else(|| self.solver.registrar().name())
+    }
+}
+
+/// Registration experiment: input frames, starting pose and solvers to compare
+#[derive(Resource, Debug, Clone, Serialize, Deserialize)]
+pub struct Experiment {
+    pub source: Frame,                // Frame to align
+    pub target: Frame,                // Reference frame
+    #[serde(default = "default_sample_step")]
+    pub sample_step: u32,             // Registration uses one pixel out of sample_step in each direction
+    #[serde(default)]
+    pub target_pose: Pose,            // Pose of the reference frame
+    #[serde(default)]
+    pub initial_pose: Pose,           // Starting pose of the frame to align
+    #[serde(default = "default_translation_radius")]
+    pub translation_radius: f32,      // Distance searched around the initial translation
+    #[serde(default = "default_rotation_radius")]
+    pub rotation_radius: f32,         // Angle searched around the initial rotation (radians)
+    pub ground_truth: Option<Pose>,   // True pose of the frame to align, if known
+    pub pose_file: Option<PoseFile>,  // Overrides the ground truth, and the initial pose if it has a frame
+    pub labels: Option<LabelOptions>, // Needs the pose file, to place the frames in the scene
+    #[serde(default = "default_translation_scale")]
+    pub translation_scale: f32,       // Scene units per meter
+    #[serde(default)]
+    pub success: SuccessThresholds,   // Thresholds of the success rate
+    pub solvers: Vec<SolverEntry>,
+    #[serde(default = "default_repeats")]
+    pub repeats: usize,               // Number of runs of every solver in the benchmark
+    pub seed: Option<u64>,            // Seed every benchmark run derives its own from, a random one when not given
+    pub output: Option<PathBuf>,      // Directory where the results are written
+    #[serde(skip)]
+    pub trajectory: Option<Trajectory>, // Read from the pose file when the experiment is loaded
+}
+
+fn default_sample_step() -> u32 {
+    30
+}
+
+fn default_repeats() -> usize {
+    1
+}
+
+fn default_translation_scale() -> f32 {
+    1.0
+}
+
+fn default_translation_radius() -> f32 {
+    1.0
+}
+
+fn default_rotation_radius() -> f32 {
+    std::f32::consts::PI
+}
+
+impl Experiment {
+    /// Loads an experiment from a TOML or JSON file, chosen by its extension
+    pub fn load<T: AsRef<Path>>(path: T) -> Result<Self, RegistrationError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .map_err(|source| RegistrationError::Io { path: path.to_path_buf(), source })?;
+
+        let mut experiment: Experiment = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::from_str(&contents).map_err(|err| err.to_string()),
+            Some("toml") => toml::from_str(&contents).map_err(|err| err.to_string()),
+            _ => Err("expected a .toml or .json file".to_string())
+        }.map_err(|reason| RegistrationError::Parse { path: path.to_path_buf(), reason })?;
+
+        let invalid = |reason: String| Err(RegistrationError::InvalidExperiment { path: path.to_path_buf(), reason });
+        if experiment.solvers.is_empty() {
+            return invalid("no solvers given".to_string());
+        }
+        if experiment.sample_step == 0 {
+            return invalid("sample_step must be at least 1".to_string());
+        }
+        for (i, entry) in experiment.solvers.iter().enumerate() {
+            if let Err(err) = entry.solver.validate() {
+                return invalid(format!("{}: {}", entry.label(), err));
+            }
+            // The benchmark rows are grouped by label
+            if experiment.solvers[..i].iter().any(|other| other.label() == entry.label()) {
+                return invalid(format!("two solvers are labelled '{}', give them distinct labels", entry.label()));
+            }
+        }
+        if experiment.translation_radius < 0.0 || experiment.rotation_radius < 0.0 {
+            return invalid("negative search radius".to_string());
+        }
+        if experiment.translation_scale <= 0.0 {
+            return invalid("translation_scale must be positive".to_string());
+        }
+        if experiment.labels.is_some() && experiment.pose_file.is_none() {
+            return invalid("labels need a pose_file".to_string());
+        }
+        if experiment.labels.is_none() {
+            if let Some(entry) = experiment.solvers.iter().find(|entry| entry.solver.correspondences().same_label) {
+                return invalid(format!("{}: same_label needs labels", entry.label()));
+            }
+        }
+        experiment.read_pose_file()?;
+
+        Ok(experiment)
+    }
+
+    /// Starting point shared by every solver
+    pub fn initial_guess(&self) -> InitialGuess {
+        InitialGuess {
+            pose: self.initial_pose.into(),
+            translation_radius: self.translation_radius,
+            rotation_radius: self.rotation_radius
+        }
+    }
+
+    // Ground truth and initial pose of the frame to align, relative to the reference frame at target_pose
+    fn read_pose_file(&mut self) -> Result<(), RegistrationError> {
+        let Some(pose_file) = &self.pose_file else {
+            return Ok(());
+        };
+        let trajectory = Trajectory::load(&pose_file.path, self.translation_scale)?;
+        let target_pose: Transform = self.target_pose.into();
+
+        let pose_of = |frame: usize| {
+            trajectory
+                .relative(pose_file.target_frame, frame)
+                .map(|relative| Pose::from(target_pose.mul_transform(relative)))
+                .ok_or_else(|| missing_frame(&pose_file.path, frame, &trajectory))
+        };
+
+        self.ground_truth = Some(pose_of(pose_file.source_frame)?);
+        if let Some(frame) = pose_file.initial_frame {
+            self.initial_pose = pose_of(frame)?;
+        }
+        self.trajectory = Some(trajectory);
+        Ok(())
+    }
+
+    /// Labelled point cloud of the scene and the poses of both frames in it, if the experiment has labels
+    pub fn load_labels(&self) -> Result<Option<FrameLabels>, RegistrationError> {
+        let (Some(options), Some(pose_file), Some(trajectory)) = (&self.labels, &self.pose_file, &self.trajectory) else {
+            return Ok(None);
+        };
+        let pose_of = |frame: usize| trajectory.pose(frame).ok_or_else(|| missing_frame(&pose_file.path, frame, trajectory));
+
+        Ok(Some(FrameLabels {
+            scene: SceneLabels::load(&options.cloud, &options.path, options.max_distance, self.translation_scale)?,
+            source_pose: pose_of(pose_file.source_frame)?,
+            target_pose: pose_of(pose_file.target_frame)?,
+        }))
+    }
+
+    /// Labels the points to register, removes the excluded classes and hands the labels to the solvers
+    pub fn label_points(
+        &mut self,
+        labels: &FrameLabels,
+        source: Vec<[f32; 3]>,
+        target: Vec<[f32; 3]>
+    ) -> (Vec<[f32; 3]>, Vec<[f32; 3]>) {
+        let excluded = self.labels.as_ref().map(|options| options.excluded.clone()).unwrap_or_default();
+        let keep = |points: Vec<[f32; 3]>, labels: Vec<u32>| -> (Vec<[f32; 3]>, Vec<u32>) {
+            points.into_iter().zip(labels).filter(|(_, label)| !excluded.contains(label)).unzip()
+        };
+
+        let source_labels = labels.source(&source);
+        let target_labels = labels.target(&target);
+        let (source, source_labels) = keep(source, source_labels);
+        let (target, target_labels) = keep(target, target_labels);
+
+        let labels = Arc::new(PointLabels { source: source_labels, target: target_labels });
+        for entry in self.solvers.iter_mut() {
+            entry.solver.set_labels(labels.clone());
+        }
+        (source, target)
+    }
+
+    /// Error of a pose of the frame to align, if the ground truth is known
+    pub fn pose_error(&self, transform: &Transform) -> Option<PoseError> {
+        let ground_truth: Transform = self.ground_truth?.into();
+        let (translation, rotation) = pose::distance(transform, &ground_truth);
+        let translation = translation / self.translation_scale;
+        let rotation = rotation.to_degrees();
+
+        Some(PoseError {
+            rotation,
+            translation,
+            success: rotation <= self.success.rotation && translation <= self.success.translation
+        })
+    }
+
+    /// Writes a results file into the output directory, if one is configured
+    pub fn write_output(&self, file_name: &str, contents: &str) -> Result<(), String> {
+        if let Some(dir) = &self.output {
+            fs::create_dir_all(dir)
+                .map_err(|err| format!("Failed to create '{}': {}", dir.display(), err))?;
+            let path = dir.join(file_name);
+            fs::write(&path, contents)
+                .map_err(|err| format!("Failed to write '{}': {}", path.display(), err))?;
+        }
+        Ok(())
+    }
+}
+
+// Frame of the experiment beyond the end of the pose file
+fn missing_frame(path: &Path, frame: usize, trajectory: &Trajectory) -> RegistrationError {
+    RegistrationError::Parse {
+        path: path.to_path_buf(),
+        reason: format!("no frame {}, the trajectory has {} frames", frame, trajectory.frames())
+    }
+}
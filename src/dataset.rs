@@ -0,0 +1,419 @@
+use std::fs;
+use std::path::Path;
+use bevy::prelude::*;
+use kiddo::{KdTree, SquaredEuclidean};
+use rayon::prelude::*;
+
+use crate::error::RegistrationError;
+
+/// Camera trajectory of a scene of the UW RGB-D dataset, read from its `scene-XX.pose` file.
+///
+/// Every line holds the pose of one frame, starting at frame 0: the rotation as a quaternion
+/// `w x y z` followed by the translation `x y z`, in meters. The poses are converted to the
+/// coordinates of the viewer, whose x and y axes are flipped with respect to the camera (see
+/// `render::compute_world_coordinates`), and the translations are scaled to scene units.
+#[derive(Debug, Clone)]
+pub struct Trajectory {
+    poses: Vec<Transform>, // Indexed by frame number
+}
+
+impl Trajectory {
+    pub fn load<T: AsRef<Path>>(path: T, translation_scale: f32) -> Result<Self, RegistrationError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .map_err(|source| RegistrationError::Io { path: path.to_path_buf(), source })?;
+
+        let poses = contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                parse_pose(line, translation_scale).ok_or_else(|| RegistrationError::Parse {
+                    path: path.to_path_buf(),
+                    reason: format!("invalid pose on line {}: '{}'", i + 1, line.trim())
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Trajectory { poses })
+    }
+
+    /// Number of frames
+    pub fn frames(&self) -> usize {
+        self.poses.len()
+    }
+
+    /// Pose of a frame, relative to the first frame of the scene
+    pub fn pose(&self, frame: usize) -> Option<Transform> {
+        self.poses.get(frame).copied()
+    }
+
+    /// Pose of a frame relative to another one: the transform that maps the points of `frame`
+    /// into the coordinates of `reference`
+    pub fn relative(&self, reference: usize, frame: usize) -> Option<Transform> {
+        let reference = self.pose(reference)?;
+        let frame = self.pose(frame)?;
+        let inverse_rotation = reference.rotation.inverse();
+        Some(Transform {
+            translation: inverse_rotation * (frame.translation - reference.translation),
+            rotation: (inverse_rotation * frame.rotation).normalize(),
+            ..Default::default()
+        })
+    }
+}
+
+// `w x y z tx ty tz`, conjugated by the flip of the x and y axes
+fn parse_pose(line: &str, translation_scale: f32) -> Option<Transform> {
+    let values: Vec<f32> = line.split_whitespace().map(|value| value.parse().ok()).collect::<Option<_>>()?;
+    let [w, x, y, z, tx, ty, tz] = values[..] else {
+        return None;
+    };
+    Some(Transform {
+        translation: Vec3::new(-tx, -ty, tz) * translation_scale,
+        rotation: Quat::from_xyzw(-x, -y, z, w).normalize(),
+        ..Default::default()
+    })
+}
+
+/// Label of the points that are not close to any labelled point of the scene
+pub const UNLABELLED: u32 = 0;
+
+/// Point cloud of a scene with the object class of every point, as in the `scene-XX.ply` and
+/// `scene-XX.label` files of the dataset. The label file starts with the number of points,
+/// followed by one class id per line, in the order of the vertices of the point cloud.
+pub struct SceneLabels {
+    tree: KdTree<f32, 3>,      // Points of the scene, in meters
+    labels: Vec<u32>,
+    max_squared_distance: f32, // Farther points are unlabelled
+    translation_scale: f32,    // Scene units per meter
+}
+
+impl SceneLabels {
+    pub fn load(
+        cloud_path: &Path,
+        label_path: &Path,
+        max_distance: f32,
+        translation_scale: f32
+    ) -> Result<Self, RegistrationError> {
+        let points = read_ply_points(cloud_path)?;
+        let labels = read_labels(label_path)?;
+        if points.len() != labels.len() {
+            return Err(RegistrationError::Parse {
+                path: label_path.to_path_buf(),
+                reason: format!("{} labels but '{}' has {} points", labels.len(), cloud_path.display(), points.len())
+            });
+        }
+
+        let mut tree = KdTree::with_capacity(points.len());
+        for (index, point) in points.iter().enumerate() {
+            tree.add(point, index as u64);
+        }
+
+        Ok(SceneLabels { tree, labels, max_squared_distance: max_distance * max_distance, translation_scale })
+    }
+
+    /// Label of the closest scene point of every point of a frame. The points are in the coordinates of
+    /// the viewer and in scene units, and `frame_pose` is the pose of their frame in the trajectory.
+    pub fn label(&self, points: &[[f32; 3]], frame_pose: &Transform) -> Vec<u32> {
+        points
+            .par_iter()
+            .map(|&point| {
+                // Back to the coordinates of the dataset, in meters
+                let point = frame_pose.transform_point(Vec3::from(point)) / self.translation_scale;
+                let nearest = self.tree.nearest_one::<SquaredEuclidean>(&[-point.x, -point.y, point.z]);
+                if nearest.distance <= self.max_squared_distance {
+                    self.labels[nearest.item as usize]
+                } else {
+                    UNLABELLED
+                }
+            })
+            .collect()
+    }
+}
+
+/// Labels of the points of the two frames of a registration
+pub struct FrameLabels {
+    pub scene: SceneLabels,
+    pub source_pose: Transform, // Poses of the frames in the trajectory of the scene
+    pub target_pose: Transform,
+}
+
+impl FrameLabels {
+    pub fn source(&self, points: &[[f32; 3]]) -> Vec<u32> {
+        self.scene.label(points, &self.source_pose)
+    }
+
+    pub fn target(&self, points: &[[f32; 3]]) -> Vec<u32> {
+        self.scene.label(points, &self.target_pose)
+    }
+}
+
+/// Class ids of a `scene-XX.label` file
+pub fn read_labels(path: &Path) -> Result<Vec<u32>, RegistrationError> {
+    let contents = fs::read_to_string(path)
+        .map_err(|source| RegistrationError::Io { path: path.to_path_buf(), source })?;
+    parse_labels(&contents).map_err(|reason| RegistrationError::Parse { path: path.to_path_buf(), reason })
+}
+
+// Number of points followed by one class id per point
+fn parse_labels(contents: &str) -> Result<Vec<u32>, String> {
+    let mut values = contents.split_whitespace();
+
+    let count: usize = values
+        .next()
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| "missing the number of points".to_string())?;
+    let labels = values
+        .map(|value| value.parse::<u32>().map_err(|_| format!("'{}' is not a class id", value)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if labels.len() != count {
+        return Err(format!("{} labels, expected {}", labels.len(), count));
+    }
+    Ok(labels)
+}
+
+/// Vertex positions of a PLY file, in ASCII or binary little-endian format
+pub fn read_ply_points(path: &Path) -> Result<Vec<[f32; 3]>, RegistrationError> {
+    let bytes = fs::read(path).map_err(|source| RegistrationError::Io { path: path.to_path_buf(), source })?;
+    parse_ply_points(&bytes).map_err(|reason| RegistrationError::Parse { path: path.to_path_buf(), reason })
+}
+
+// Only the vertex element is read, it has to be the first one
+fn parse_ply_points(bytes: &[u8]) -> Result<Vec<[f32; 3]>, String> {
+    // The header ends with a line feed, possibly preceded by a carriage return
+    let header_end = bytes
+        .windows(10)
+        .position(|window| window == b"end_header")
+        .ok_or_else(|| "missing end_header".to_string())?;
+    let body_start = bytes[header_end..]
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .map(|offset| header_end + offset + 1)
+        .ok_or_else(|| "missing line feed after end_header".to_string())?;
+    let header = String::from_utf8_lossy(&bytes[..header_end]);
+    let body = &bytes[body_start..];
+
+    let mut binary = false;
+    let mut vertex_count = None;
+    let mut properties: Vec<(String, usize)> = Vec::new(); // Name and size in bytes of every vertex property
+    for line in header.lines() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            ["format", "ascii", _] => binary = false,
+            ["format", "binary_little_endian", _] => binary = true,
+            ["format", format, _] => return Err(format!("unsupported format '{}'", format)),
+            ["element", "vertex", count] if vertex_count.is_none() => {
+                vertex_count = Some(count.parse::<usize>().map_err(|_| format!("invalid vertex count '{}'", count))?);
+            }
+            ["element", ..] if vertex_count.is_some() => break,
+            ["element", name, ..] => return Err(format!("the first element is '{}', expected 'vertex'", name)),
+            ["property", kind, name] if vertex_count.is_some() => {
+                let size = match kind {
+                    "char" | "uchar" | "int8" | "uint8" => 1,
+                    "short" | "ushort" | "int16" | "uint16" => 2,
+                    "int" | "uint" | "float" | "int32" | "uint32" | "float32" => 4,
+                    "double" | "float64" => 8,
+                    _ => return Err(format!("unsupported property type '{}'", kind)),
+                };
+                properties.push((name.to_string(), size));
+            }
+            _ => {}
+        }
+    }
+    let count = vertex_count.ok_or_else(|| "no vertex element".to_string())?;
+
+    // Position of x, y and z among the properties, which must be floats
+    let mut coordinates = [0; 3];
+    for (coordinate, name) in coordinates.iter_mut().zip(["x", "y", "z"]) {
+        *coordinate = properties
+            .iter()
+            .position(|(property, size)| property == name && *size == 4)
+            .ok_or_else(|| format!("no float vertex property '{}'", name))?;
+    }
+
+    if binary {
+        let offsets: Vec<usize> = properties.iter().scan(0, |offset, (_, size)| {
+            let start = *offset;
+            *offset += size;
+            Some(start)
+        }).collect();
+        let stride: usize = properties.iter().map(|(_, size)| size).sum();
+        if body.len() < count * stride {
+            return Err("truncated vertex data".to_string());
+        }
+        Ok(body
+            .chunks_exact(stride)
+            .take(count)
+            .map(|vertex| {
+                coordinates.map(|c| f32::from_le_bytes(vertex[offsets[c]..offsets[c] + 4].try_into().unwrap()))
+            })
+            .collect())
+    } else {
+        let points = String::from_utf8_lossy(body)
+            .lines()
+            .take(count)
+            .map(|line| {
+                let values: Vec<&str> = line.split_whitespace().collect();
+                let mut point = [0.0; 3];
+                for (value, &c) in point.iter_mut().zip(coordinates.iter()) {
+                    *value = values
+                        .get(c)
+                        .and_then(|v| v.parse().ok())
+                        .ok_or_else(|| format!("invalid vertex '{}'", line.trim()))?;
+                }
+                Ok(point)
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        if points.len() != count {
+            return Err("truncated vertex data".to_string());
+        }
+        Ok(points)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Identity, 90 degrees about z and 45 degrees about x, with the scale of the default experiment
+    fn trajectory() -> Trajectory {
+        let lines = [
+            "1 0 0 0 0 0 0",
+            "0.70710677 0 0 0.70710677 1 2 3",
+            "0.9238795 0.3826834 0 0 0.5 -0.25 1",
+        ];
+        Trajectory { poses: lines.iter().map(|line| parse_pose(line, 10.0).unwrap()).collect() }
+    }
+
+    fn assert_transform_eq(a: Transform, b: Transform) {
+        assert!(a.translation.abs_diff_eq(b.translation, 1e-4), "{:?} != {:?}", a.translation, b.translation);
+        assert!(a.rotation.angle_between(b.rotation) < 1e-3, "{:?} != {:?}", a.rotation, b.rotation);
+    }
+
+    #[test]
+    fn parses_pose() {
+        let pose = parse_pose("0.70710677 0 0 0.70710677 1 2 3", 10.0).unwrap();
+        assert_eq!(pose.translation, Vec3::new(-10.0, -20.0, 30.0));
+        assert!(pose.rotation.angle_between(Quat::from_rotation_z(std::f32::consts::FRAC_PI_2)) < 1e-5);
+
+        // The x and y axes are flipped, so a rotation about x keeps its sign only about z
+        let pose = parse_pose("0.9238795 0.3826834 0 0 0 0 0", 1.0).unwrap();
+        assert!(pose.rotation.angle_between(Quat::from_rotation_x(-std::f32::consts::FRAC_PI_4)) < 1e-5);
+
+        assert_eq!(parse_pose("1 0 0 0 0 0", 1.0), None);
+        assert_eq!(parse_pose("1 0 0 0 0 0 0 0", 1.0), None);
+        assert_eq!(parse_pose("1 0 0 0 0 0 x", 1.0), None);
+    }
+
+    #[test]
+    fn relative_pose_of_a_frame_to_itself_is_the_identity() {
+        let trajectory = trajectory();
+        for frame in 0..trajectory.frames() {
+            assert_transform_eq(trajectory.relative(frame, frame).unwrap(), Transform::IDENTITY);
+        }
+        assert_eq!(trajectory.relative(0, trajectory.frames()), None);
+    }
+
+    #[test]
+    fn relative_poses_are_inverse() {
+        let trajectory = trajectory();
+        for (a, b) in [(0, 1), (1, 2), (2, 0)] {
+            let forward = trajectory.relative(a, b).unwrap();
+            let backward = trajectory.relative(b, a).unwrap();
+            assert_transform_eq(forward * backward, Transform::IDENTITY);
+        }
+    }
+
+    #[test]
+    fn relative_pose_of_a_known_frame() {
+        // Frame 0 seen from frame 1: the offset (10, 20, -30) rotated by -90 degrees about z
+        let relative = trajectory().relative(1, 0).unwrap();
+        let expected = Transform {
+            translation: Vec3::new(20.0, -10.0, -30.0),
+            rotation: Quat::from_rotation_z(-std::f32::consts::FRAC_PI_2),
+            ..Default::default()
+        };
+        assert_transform_eq(relative, expected);
+
+        // Maps the points of frame 0 into frame 1, so the origin of frame 1 maps to itself
+        let origin = trajectory().pose(1).unwrap().translation;
+        assert!(relative.transform_point(origin).abs_diff_eq(Vec3::new(0.0, 0.0, 0.0), 1e-4));
+    }
+
+    // Binary PLY with a color byte before the coordinates, to check the offsets
+    fn binary_ply(line_end: &str, points: &[[f32; 3]]) -> Vec<u8> {
+        let header = [
+            "ply",
+            "format binary_little_endian 1.0",
+            &format!("element vertex {}", points.len()),
+            "property uchar red",
+            "property float x",
+            "property float y",
+            "property float z",
+            "element face 0",
+            "property list uchar int vertex_indices",
+            "end_header",
+        ];
+        let mut bytes: Vec<u8> = header.iter().flat_map(|line| format!("{}{}", line, line_end).into_bytes()).collect();
+        for point in points {
+            bytes.push(255);
+            for value in point {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn parses_binary_ply() {
+        let points = [[1.0, -2.0, 3.5], [0.25, 0.0, -7.0]];
+        assert_eq!(parse_ply_points(&binary_ply("\n", &points)), Ok(points.to_vec()));
+    }
+
+    #[test]
+    fn parses_ply_with_crlf_header() {
+        let points = [[1.0, 2.0, 3.0]];
+        assert_eq!(parse_ply_points(&binary_ply("\r\n", &points)), Ok(points.to_vec()));
+
+        let ascii = "ply\r\nformat ascii 1.0\r\nelement vertex 2\r\nproperty float x\r\nproperty float y\r\n\
+            property float z\r\nend_header\r\n1 2 3\r\n4 5 6\r\n";
+        assert_eq!(parse_ply_points(ascii.as_bytes()), Ok(vec![[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]]));
+    }
+
+    #[test]
+    fn rejects_ply_without_leading_vertex_element() {
+        let ply = "ply\nformat ascii 1.0\nelement face 0\nproperty list uchar int vertex_indices\n\
+            element vertex 1\nproperty float x\nproperty float y\nproperty float z\nend_header\n1 2 3\n";
+        assert!(parse_ply_points(ply.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_ply() {
+        let mut bytes = binary_ply("\n", &[[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]]);
+        bytes.truncate(bytes.len() - 1);
+        assert!(parse_ply_points(&bytes).is_err());
+    }
+
+    #[test]
+    fn parses_labels() {
+        assert_eq!(parse_labels("3\n10\n1\n10\n"), Ok(vec![10, 1, 10]));
+        assert_eq!(parse_labels("2\r\n4\r\n0\r\n"), Ok(vec![4, 0]));
+    }
+
+    #[test]
+    fn missing_files_are_io_errors() {
+        let path = Path::new("assets/missing-file");
+        assert!(matches!(read_labels(path), Err(RegistrationError::Io { .. })));
+        assert!(matches!(read_ply_points(path), Err(RegistrationError::Io { .. })));
+        assert!(matches!(Trajectory::load(path, 1.0), Err(RegistrationError::Io { .. })));
+    }
+
+    #[test]
+    fn rejects_label_count_mismatch() {
+        assert!(parse_labels("3\n10\n1\n").is_err());
+        assert!(parse_labels("1\n10\n1\n").is_err());
+        assert!(parse_labels("").is_err());
+        assert!(parse_labels("2\n1\nbackground\n").is_err());
+    }
+}
@@ -0,0 +1,44 @@
+use std::{fmt, io};
+use std::path::PathBuf;
+
+/// Errors raised while loading the experiment, the frames and the dataset files, or registering the frames
+#[derive(Debug)]
+pub enum RegistrationError {
+    EmptyPointCloud,
+    UnsupportedImageFormat { path: PathBuf, reason: String },
+    DimensionMismatch { color: (u32, u32), depth: (u32, u32) },
+    Io { path: PathBuf, source: io::Error },
+    Parse { path: PathBuf, reason: String },             // Malformed experiment, pose, label or PLY file
+    InvalidExperiment { path: PathBuf, reason: String }, // Well-formed experiment with unusable values
+    NotConverged { solver: String },
+}
+
+impl fmt::Display for RegistrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistrationError::EmptyPointCloud =>
+                write!(f, "Source or target point cloud is empty."),
+            RegistrationError::UnsupportedImageFormat { path, reason } =>
+                write!(f, "Unsupported image '{}': {}", path.display(), reason),
+            RegistrationError::DimensionMismatch { color, depth } =>
+                write!(f, "Color image is {}x{} but depth image is {}x{}", color.0, color.1, depth.0, depth.1),
+            RegistrationError::Io { path, source } =>
+                write!(f, "Failed to read '{}': {}", path.display(), source),
+            RegistrationError::Parse { path, reason } =>
+                write!(f, "Failed to parse '{}': {}", path.display(), reason),
+            RegistrationError::InvalidExperiment { path, reason } =>
+                write!(f, "Invalid experiment '{}': {}", path.display(), reason),
+            RegistrationError::NotConverged { solver } =>
+                write!(f, "{} failed to find a solution.", solver),
+        }
+    }
+}
+
+impl std::error::Error for RegistrationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RegistrationError::Io { source, .. } => Some(source),
+            _ => None
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use crate::config::{Experiment, SolverEntry, DEFAULT_EXPERIMENT_PATH};
+use crate::render::rgbd_to_points;
+use crate::solvers::Solver;
+use crate::utils::FitnessContext;
+
+const USAGE: &str = "Usage: headless [options]
+    --config <path>               Experiment file (default: experiments/default.toml)
+    --solver <name>               Run only this solver with its default parameters
+                                  (ICP, PlaneICP, GICP, GA, ES, CMAES, PSO or DE)
+    --source-color <path>         Color image of the frame to align
+    --source-depth <path>         Depth image of the frame to align
+    --target-color <path>         Color image of the reference frame
+    --target-depth <path>         Depth image of the reference frame
+    --output <path>               Write the result to a file
+    --seed <number>               Seed of the stochastic solvers, to replay a run
+    --verbose                     Print solver progress";
+
+struct Options {
+    config: String,
+    solver: Option<Solver>,
+    source_color: Option<PathBuf>,
+    source_depth: Option<PathBuf>,
+    target_color: Option<PathBuf>,
+    target_depth: Option<PathBuf>,
+    output: Option<String>,
+    seed: Option<u64>,
+    verbose: bool
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        config: DEFAULT_EXPERIMENT_PATH.to_string(),
+        solver: None,
+        source_color: None,
+        source_depth: None,
+        target_color: None,
+        target_depth: None,
+        output: None,
+        seed: None,
+        verbose: false
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        // Flags without value
+        match arg.as_str() {
+            "--verbose" => { options.verbose = true; continue; }
+            "--help" => return Err(USAGE.to_string()),
+            _ => {}
+        }
+
+        let value = args.next()
+            .ok_or_else(|| format!("Missing value for '{}'\n{}", arg, USAGE))?
+            .clone();
+        match arg.as_str() {
+            "--config" => options.config = value,
+            "--solver" => options.solver = Some(value.parse()?),
+            "--source-color" => options.source_color = Some(value.into()),
+            "--source-depth" => options.source_depth = Some(value.into()),
+            "--target-color" => options.target_color = Some(value.into()),
+            "--target-depth" => options.target_depth = Some(value.into()),
+            "--output" => options.output = Some(value),
+            "--seed" => options.seed = Some(value.parse().map_err(|_| format!("Invalid seed '{}'", value))?),
+            _ => return Err(format!("Unknown option '{}'\n{}", arg, USAGE))
+        }
+    }
+
+    Ok(options)
+}
+
+/// Loads the experiment and applies the command line overrides
+fn load_experiment(options: Options) -> Result<(Experiment, Option<String>), String> {
+    let mut experiment = Experiment::load(&options.config).map_err(|err| err.to_string())?;
+
+    if let Some(solver) = options.solver {
+        experiment.solvers = vec![SolverEntry { solver, label: None }];
+    }
+    if let Some(path) = options.source_color { experiment.source.color = path; }
+    if let Some(path) = options.source_depth { experiment.source.depth = path; }
+    if let Some(path) = options.target_color { experiment.target.color = path; }
+    if let Some(path) = options.target_depth { experiment.target.depth = path; }
+    for entry in experiment.solvers.iter_mut() {
+        entry.solver.set_verbose(options.verbose);
+        if let Some(seed) = options.seed {
+            entry.solver.set_seed(seed);
+        }
+    }
+
+    Ok((experiment, options.output))
+}
+
+/// Registers the source frame against the target frame with every solver of the experiment
+pub fn run(args: &[String]) -> Result<(), String> {
+    let (mut experiment, output) = load_experiment(parse_args(args)?)?;
+
+    // Only the point extraction is needed, no rendering plugins are initialized
+    let source = rgbd_to_points(&experiment.source.color, &experiment.source.depth, experiment.sample_step)
+        .map_err(|err| err.to_string())?
+        .filtered_positions;
+    let target = rgbd_to_points(&experiment.target.color, &experiment.target.depth, experiment.sample_step)
+        .map_err(|err| err.to_string())?
+        .filtered_positions;
+
+    // Without the excluded classes, and with the labels for the class-aware correspondences
+    let (source, target) = match experiment.load_labels().map_err(|err| err.to_string())? {
+        Some(labels) => experiment.label_points(&labels, source, target),
+        None => (source, target)
+    };
+    println!("Source: {} points | Target: {} points", source.len(), target.len());
+
+    let context = FitnessContext::new(&source, &target);
+    let initial_guess = experiment.initial_guess();
+
+    let mut report = String::new();
+    for entry in experiment.solvers.iter() {
+        let registrar = entry.solver.registrar();
+
+        // Solve problem and get duration
+        let start = Instant::now();
+        let result = match registrar.register(&source, &target, &initial_guess) {
+            Ok(result) => result,
+            Err(err) => {
+                // Keep going with the remaining solvers
+                eprintln!("Solver: {} | Algorithm failed: {}", entry.label(), err);
+                report += &format!("solver {}\nerror {}\n", entry.label(), err);
+                continue;
+            }
+        };
+        let duration = start.elapsed();
+        let transform = result.transform;
+
+        // Compute error
+        let error = context.evaluate(&transform);
+
+        // Error with respect to the ground truth, if known
+        let pose_error = experiment.pose_error(&transform);
+
+        report += &format!(
+            "solver {}\ntranslation {} {} {}\nrotation {} {} {} {}\nresidual {}\ntime {}\n\
+            fitness {}\niterations {}\nevaluations {}\ntermination {:?}\noverlap {}\nseed {}\n{}history {}\n",
+            entry.label(),
+            transform.translation.x, transform.translation.y, transform.translation.z,
+            transform.rotation.x, transform.rotation.y, transform.rotation.z, transform.rotation.w,
+            error,
+            duration.as_secs_f64(),
+            result.fitness,
+            result.iterations,
+            result.evaluations,
+            result.termination,
+            result.overlap,
+            result.seed.map(|seed| seed.to_string()).unwrap_or_else(|| "none".to_string()),
+            pose_error.map(|e| format!(
+                "rotation_error {}\ntranslation_error {}\nsuccess {}\n", e.rotation, e.translation, e.success
+            )).unwrap_or_default(),
+            result.history.iter().map(f32::to_string).collect::<Vec<_>>().join(" ")
+        );
+    }
+    print!("{}", report);
+
+    match output {
+        Some(path) => fs::write(&path, report).map_err(|err| format!("Failed to write '{}': {}", path, err)),
+        None => experiment.write_output("registration.txt", &report)
+    }
+}
@@ -5,37 +5,51 @@ use bevy_flycam::prelude::*;
 use lazy::dsl::col;
 use lazy::frame::IntoLazy;
 use polars::frame::DataFrame;
-use polars::prelude::NamedFrom;
+use polars::prelude::{DataType, NamedFrom};
 use polars::*;
 
-use config::{CORRECT_POSE2, POSE1, POSE2};
+use config::{Experiment, PoseError, SolverEntry, DEFAULT_EXPERIMENT_PATH};
+use report::Metadata;
 use series::Series;
 use spawn::*;
-use solvers::icp::iterative_closest_point;
-use solvers::ga::genetic_algorithm;
-use solvers::es::evolution_strategy;
-use solvers::pso::particle_swarm_optimization;
-use solvers::de::differential_evolution;
-use utils::fitness;
+use rand::Rng;
+use solvers::Solver;
+use solvers::de::Mode;
+use solvers::random::RandomStreams;
+use utils::FitnessContext;
 
 mod solvers;
 mod render;
 mod spawn;
 mod utils;
+mod robust;
 mod config;
-
-const IMG1_COLOR_PATH: &str = "assets/00000-color.png";
-const IMG1_DEPTH_PATH: &str = "assets/00000-depth.png";
-const IMG2_COLOR_PATH: &str = "assets/00050-color.png";
-const IMG2_DEPTH_PATH: &str = "assets/00050-depth.png";
-/*const IMG3_COLOR_PATH: &str = "assets/00100-color.png";
-const IMG3_DPETH_PATH: &str = "assets/00100-depth.png";
-const IMG4_COLOR_PATH: &str = "assets/00150-color.png";
-const IMG4_DEPTH_PATH: &str = "assets/00150-depth.png";
-const IMG5_COLOR_PATH: &str = "assets/00200-color.png";
-const IMG5_DEPTH_PATH: &str = "assets/00200-depth.png";*/
+mod dataset;
+mod error;
+mod headless;
+mod report;
 
 fn main() {
+    // Run a registration without opening a window: `<binary> headless [options]`
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("headless") {
+        if let Err(err) = headless::run(&args[2..]) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Otherwise open the viewer: `<binary> [experiment file]`
+    let experiment_path = args.get(1).map(String::as_str).unwrap_or(DEFAULT_EXPERIMENT_PATH);
+    let experiment = match Experiment::load(experiment_path) {
+        Ok(experiment) => experiment,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
     App::new()
         .add_plugins(DefaultPlugins)
         .add_plugins(NoCameraPlayerPlugin)
@@ -43,7 +57,8 @@ fn main() {
             sensitivity: 0.00015, // default: 0.00012
             speed: 10.0,          // default: 12.0
         })
-        .insert_resource(CameraTransform(POSE2))
+        .insert_resource(CameraTransform(experiment.initial_pose.into()))
+        .insert_resource(experiment)
         .insert_resource(PointClouds{ source: Vec::new(), target: Vec::new() })
         .add_systems(Startup, setup)
         .add_systems(Update, (
@@ -61,19 +76,45 @@ fn setup(
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
     mut point_clouds: ResMut<PointClouds>,
-    camera_transform: Res<CameraTransform>
+    camera_transform: Res<CameraTransform>,
+    mut experiment: ResMut<Experiment>
 ) {
     // Reference pose
-    let pose1: Transform = POSE1;
+    let pose1: Transform = experiment.target_pose.into();
 
-    // Initial IMG2 pose
-    let pose2 = POSE2;
+    // Initial pose of the frame to align
+    let pose2: Transform = experiment.initial_pose.into();
+
+    // Classes of the points, if the experiment has labels
+    let labels = match experiment.load_labels() {
+        Ok(labels) => labels,
+        Err(err) => {
+            eprintln!("Failed to load the labels: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let label_colors = labels.as_ref().filter(|_| experiment.labels.as_ref().is_some_and(|options| options.colors));
 
     // Spawn reference mesh
-    spawn_mesh(&mut commands, &mut meshes, &mut materials, &mut point_clouds, IMG1_COLOR_PATH, IMG1_DEPTH_PATH, pose1, false);
+    let target = &experiment.target;
+    if let Err(err) = spawn_mesh(&mut commands, &mut meshes, &mut materials, &mut point_clouds, &target.color, &target.depth, experiment.sample_step, pose1, false, label_colors) {
+        eprintln!("Failed to load the reference frame: {}", err);
+        std::process::exit(1);
+    }
 
     // Spawn predicted mesh
-    spawn_mesh(&mut commands, &mut meshes, &mut materials, &mut point_clouds, IMG2_COLOR_PATH, IMG2_DEPTH_PATH, pose2, true);
+    let source = &experiment.source;
+    if let Err(err) = spawn_mesh(&mut commands, &mut meshes, &mut materials, &mut point_clouds, &source.color, &source.depth, experiment.sample_step, pose2, true, label_colors) {
+        eprintln!("Failed to load the frame to align: {}", err);
+        std::process::exit(1);
+    }
+
+    // Registration points without the excluded classes
+    if let Some(labels) = &labels {
+        let source = mem::take(&mut point_clouds.source);
+        let target = mem::take(&mut point_clouds.target);
+        (point_clouds.source, point_clouds.target) = experiment.label_points(labels, source, target);
+    }
     
     // Spawn correspondences
     spawn_correspondences(&mut commands, &mut meshes, &mut materials, point_clouds.into(), camera_transform, Visibility::Hidden);
@@ -103,6 +144,7 @@ fn setup(
 fn input_handler(
     keyboard_input: Res<ButtonInput<KeyCode>>,
     point_clouds: Res<PointClouds>,
+    experiment: Res<Experiment>,
     mut object_position: ResMut<CameraTransform>,
     mut param_set: ParamSet<(
         Query<&mut Visibility, With<ToggleImage>>,
@@ -125,17 +167,19 @@ fn input_handler(
 
     // Execute algorithm
     if keyboard_input.just_pressed(KeyCode::KeyE) {
-        run_algorithm(point_clouds, &mut object_position);
+        run_algorithm(point_clouds, &experiment, &mut object_position);
     }
 
     // Reset position
     if keyboard_input.just_pressed(KeyCode::KeyR) {
-        object_position.0 = POSE2;
+        object_position.0 = experiment.initial_pose.into();
     }
 
     // Show true position (V for Verity)
     if keyboard_input.just_pressed(KeyCode::KeyV) {
-        object_position.0 = CORRECT_POSE2;
+        if let Some(ground_truth) = experiment.ground_truth {
+            object_position.0 = ground_truth.into();
+        }
     }
 }
 
@@ -220,46 +264,40 @@ fn update_text(
     }
 }
 
-#[derive(Debug)]
-enum Solver {
-    ICP,
-    GA,
-    ES,
-    PSO,
-    DE
-}
-
-impl Solver {
-    fn to_str(&self) -> &str {
-        match self {
-            Solver::ICP => "ICP",
-            Solver::GA => "GA",
-            Solver::ES => "ES",
-            Solver::PSO => "PSO",
-            Solver::DE => "DE"
-        }
-    }
+// Outcome of one run of the benchmark
+struct Run {
+    solver: String,
+    repetition: u32,
+    seed: Option<u64>,
+    error: f32,                    // Residual error
+    time: f64,                     // Seconds
+    evaluations: u32,
+    overlap: f32,
+    pose_error: Option<PoseError>, // With respect to the ground truth, if known
 }
 
 fn run_algorithm(
     point_clouds: Res<PointClouds>,
+    experiment: &Experiment,
     object_position: &mut ResMut<CameraTransform>
 ) {
     let source_points = &point_clouds.source;
     let target_points = &point_clouds.target;
 
-    let source: Vec<Vec3> = source_points.iter().map(|&p| Vec3::from(p)).collect();
-    let target: Vec<Vec3> = target_points.iter().map(|&p| Vec3::from(p)).collect();
+    // Residual error used to compare the solvers
+    let context = FitnessContext::new(source_points, target_points);
 
     /*println!("Running algorithm!");
 
     // Change to try other algorithms
-    let solver = Solver::GA;
+    let mut solver: Solver = "GA".parse().unwrap();
+    solver.set_verbose(true);
 
-    let result = solve(source_points, target_points, &solver, true);
+    let result = solver.registrar().register(source_points, target_points, &experiment.initial_guess());
 
     match result {
-        Ok(transform) => {
+        Ok(result) => {
+            let transform = result.transform;
             println!("Algorithm succeeded!");
             println!("Translation: {:?}", transform.translation);
             println!("Rotation: {:?}", transform.rotation);
@@ -277,51 +315,71 @@ fn run_algorithm(
     println!("Running test");
     let mut best_transform = Transform::default();
     let mut best_score = f32::INFINITY;
-    let variants = [Solver::ICP, Solver::GA, Solver::ES, Solver::PSO, Solver::DE];
-    let num_repeats = 30;
+    let num_repeats = experiment.repeats;
+    let initial_guess = experiment.initial_guess();
+
+    // Every run gets its own seed, so the whole table is reproduced from this one
+    let streams = RandomStreams::new(experiment.seed);
+    println!("Seed: {}", streams.seed());
 
     // Collect results
     let mut results = Vec::new();
+    let mut convergence = String::from("solver,repetition,iteration,fitness\n");
 
     for r in 0..num_repeats {
         println!("Repetition: {}", r);
-        for solver in variants.iter() {
+        for (s, entry) in experiment.solvers.iter().enumerate() {
+            let mut solver = entry.solver.clone();
+            let seed = streams.stream(r, s).gen();
+            solver.set_seed(seed);
+            let registrar = solver.registrar();
+            let label = entry.label();
+
             // Solve problem and get duration
             let start = Instant::now();
-            let result = solve(source_points, target_points, solver, false);
+            let result = registrar.register(source_points, target_points, &initial_guess);
             let duration = start.elapsed();
 
             match result {
-                Ok(transform) => {
+                Ok(result) => {
                     // Compute error
-                    let error = fitness(&transform, &source, &target);
-                    println!("Solver: {:<3} | Residual error: {:<10} | Time: {:?}", 
-                        solver.to_str(), error, duration
+                    let transform = result.transform;
+                    let error = context.evaluate(&transform);
+                    let pose_error = experiment.pose_error(&transform);
+                    println!("Solver: {:<3} | Residual error: {:<10} | Time: {:?} | Evaluations: {:<6} | Overlap: {:.2} | {:?}{}{}",
+                        label, error, duration, result.evaluations, result.overlap, result.termination,
+                        pose_error.map(|e| format!(" | Rotation error: {:.2}° | Translation error: {:.3} m", e.rotation, e.translation))
+                            .unwrap_or_default(),
+                        result.seed.map(|seed| format!(" | Seed: {}", seed)).unwrap_or_default()
                     );
 
                     // Get best transform
                     if error < best_score {
-                        match solver { 
-                            Solver::PSO => {},
-                            _ => { // PSO is not reliable, so we discard it as global best
-                                best_transform = transform;
-                                best_score = error;
-                            }
-                        }
+                        best_transform = transform;
+                        best_score = error;
                     }
 
                     // Save results
-                    results.push((
-                        solver.to_str().to_string(),
+                    results.push(Run {
+                        solver: label.to_string(),
+                        repetition: r as u32,
+                        seed: result.seed,
                         error,
-                        duration.as_secs_f64(),
-                    ));
+                        time: duration.as_secs_f64(),
+                        evaluations: result.evaluations as u32,
+                        overlap: result.overlap,
+                        pose_error,
+                    });
+                    for (i, best_fitness) in result.history.iter().enumerate() {
+                        convergence += &format!("{},{},{},{}\n", label, r, i, best_fitness);
+                    }
                 }
                 Err(err) => {
                     eprintln!(
-                        "Solver: {:<3} | Algorithm failed: {}",
-                        solver.to_str(),
-                        err
+                        "Solver: {:<3} | Algorithm failed: {} | Seed: {}",
+                        label,
+                        err,
+                        seed
                     );
                 }
             }
@@ -331,27 +389,63 @@ fn run_algorithm(
     // Convert results into Series
     let solver_series = Series::new(
         "Solver".into(),
-        results.iter().map(|(solver, _, _)| solver.as_str()).collect::<Vec<_>>(),
+        results.iter().map(|run| run.solver.as_str()).collect::<Vec<_>>(),
+    );
+    let repetition_series = Series::new(
+        "Repetition".into(),
+        results.iter().map(|run| run.repetition).collect::<Vec<_>>(),
+    );
+    let seed_series = Series::new(
+        "Seed".into(),
+        results.iter().map(|run| run.seed).collect::<Vec<_>>(),
     );
     let error_series = Series::new(
         "Residual Error".into(),
-        results.iter().map(|(_, error, _)| *error).collect::<Vec<_>>(),
+        results.iter().map(|run| run.error).collect::<Vec<_>>(),
     );
     let time_series = Series::new(
         "Time Taken (s)".into(),
-        results.iter().map(|(_, _, time)| *time).collect::<Vec<_>>(),
+        results.iter().map(|run| run.time).collect::<Vec<_>>(),
+    );
+    let evaluations_series = Series::new(
+        "Evaluations".into(),
+        results.iter().map(|run| run.evaluations).collect::<Vec<_>>(),
+    );
+    let overlap_series = Series::new(
+        "Overlap".into(),
+        results.iter().map(|run| run.overlap).collect::<Vec<_>>(),
+    );
+    // Errors with respect to the ground truth, null when it is not known
+    let rotation_error_series = Series::new(
+        "Rotation Error (deg)".into(),
+        results.iter().map(|run| run.pose_error.map(|e| e.rotation)).collect::<Vec<_>>(),
+    );
+    let translation_error_series = Series::new(
+        "Translation Error (m)".into(),
+        results.iter().map(|run| run.pose_error.map(|e| e.translation)).collect::<Vec<_>>(),
+    );
+    let success_series = Series::new(
+        "Success".into(),
+        results.iter().map(|run| run.pose_error.map(|e| e.success)).collect::<Vec<_>>(),
     );
 
     // Create DataFrame
-    let df = DataFrame::new(vec![
+    let mut df = DataFrame::new(vec![
         solver_series.into(), 
+        repetition_series.into(),
+        seed_series.into(),
         error_series.into(), 
-        time_series.into()
+        time_series.into(),
+        evaluations_series.into(),
+        overlap_series.into(),
+        rotation_error_series.into(),
+        translation_error_series.into(),
+        success_series.into()
     ]).unwrap();
     //println!("{}", df);
 
     // Group by Solver and calculate averages
-    let lazy_df = df.lazy();
+    let lazy_df = df.clone().lazy();
     let agg_df = lazy_df
         .group_by([col("Solver")])
         .agg([
@@ -359,13 +453,33 @@ fn run_algorithm(
             col("Residual Error").std(1).alias("Std. Dev. Error"),
             col("Time Taken (s)").mean().alias("Mean Time (s)"),
             col("Time Taken (s)").std(1).alias("Std. Dev. Time (s)"),
+            col("Evaluations").mean().alias("Mean Evaluations"),
+            col("Overlap").mean().alias("Mean Overlap"),
+            col("Rotation Error (deg)").mean().alias("Mean Rotation Error (deg)"),
+            col("Rotation Error (deg)").median().alias("Median Rotation Error (deg)"),
+            col("Translation Error (m)").mean().alias("Mean Translation Error (m)"),
+            col("Translation Error (m)").median().alias("Median Translation Error (m)"),
+            col("Success").cast(DataType::Float64).mean().alias("Success Rate"),
         ])
         .sort(["Mean Error"], Default::default())
         .collect()
         .unwrap();
+    let mut agg_df = with_speedup(agg_df, experiment);
 
     // Display the aggregated results
     println!("{}", agg_df);
+    if let Err(err) = experiment.write_output("benchmark.txt", &agg_df.to_string()) {
+        eprintln!("{}", err);
+    }
+    // Best fitness after every iteration, to plot the convergence curves
+    if let Err(err) = experiment.write_output("convergence.csv", &convergence) {
+        eprintln!("{}", err);
+    }
+    // Raw and aggregated results with their metadata, for the analysis notebooks
+    let metadata = Metadata::new(experiment, streams.seed());
+    if let Err(err) = report::write_benchmark(experiment, &metadata, &mut df, &mut agg_df) {
+        eprintln!("{}", err);
+    }
     
     // Update the Transform
     object_position.0.translation = best_transform.translation;
@@ -373,59 +487,36 @@ fn run_algorithm(
 
 }
 
-fn solve(source_points: &Vec<[f32; 3]>, target_points: &Vec<[f32; 3]>, solver: &Solver, verbose: bool) 
--> Result<Transform, String> {
-    let result = match solver {
-        Solver::ICP => iterative_closest_point(
-            &source_points, 
-            &target_points, 
-            100, 
-            0.5,
-            verbose
-        ),
-        Solver::GA => genetic_algorithm(
-            &source_points, 
-            &target_points, 
-            100, 
-            100, 
-            0.3, 
-            3,
-            0.5,
-            100,
-            verbose
-        ),
-        Solver::ES => evolution_strategy(
-            &source_points,
-            &target_points,
-            100,
-            100,
-            0.1,
-            0.5,
-            verbose
-        ),
-        Solver::PSO => particle_swarm_optimization(
-            &source_points,
-            &target_points,
-            100,
-            100,
-            0.7298,
-            1.0,
-            2.1,
-            1.2,
-            0.0,
-            0.5,
-            verbose
-        ),
-        Solver::DE => differential_evolution(
-            &source_points,
-            &target_points,
-            100,
-            100,
-            0.5,
-            0.5,
-            0.5,
-            verbose
-        )
+// Adds the speedup of every DE entry over the first asynchronous one, whose trials are evaluated one at a time
+fn with_speedup(mut agg_df: DataFrame, experiment: &Experiment) -> DataFrame {
+    let de_labels: Vec<&str> = experiment.solvers
+        .iter()
+        .filter(|entry| matches!(entry.solver, Solver::DE(_)))
+        .map(SolverEntry::label)
+        .collect();
+    let Some(reference_label) = experiment.solvers.iter().find_map(|entry| match &entry.solver {
+        Solver::DE(de) if de.mode == Mode::Asynchronous => Some(entry.label()),
+        _ => None
+    }) else {
+        return agg_df;
     };
-    result
-}
\ No newline at end of file
+
+    let labels: Vec<String> = agg_df.column("Solver").unwrap().str().unwrap()
+        .into_iter()
+        .map(|label| label.unwrap_or_default().to_string())
+        .collect();
+    let times: Vec<Option<f64>> = agg_df.column("Mean Time (s)").unwrap().f64().unwrap().into_iter().collect();
+
+    let Some(reference) = labels.iter().position(|label| label == reference_label).and_then(|i| times[i]) else {
+        return agg_df;
+    };
+    let speedup: Vec<Option<f64>> = labels
+        .iter()
+        .zip(times.iter())
+        .map(|(label, time)| {
+            if de_labels.contains(&label.as_str()) { time.map(|time| reference / time) } else { None }
+        })
+        .collect();
+    agg_df.with_column(Series::new(format!("Speedup vs {}", reference_label).into(), speedup)).unwrap();
+    agg_df
+}
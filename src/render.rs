@@ -1,6 +1,9 @@
 use std::path::Path;
 use bevy::{asset::RenderAssetUsages, prelude::*, render::mesh::PrimitiveTopology};
-use image::{DynamicImage, Pixel};
+use image::{DynamicImage, ImageError, Pixel};
+
+use crate::dataset::UNLABELLED;
+use crate::error::RegistrationError;
 /*use rand::seq::SliceRandom;
 use rand::thread_rng;*/
 
@@ -10,61 +13,111 @@ pub const CONSTANT: f32 = 570.3;             // Focal length (in pixels, typical
 pub const MM_PER_M: f32 = 1000.0;            // Conversion factor (millimeters to meters)
 pub const NO_VALUE: f32 = 0.0;
 
-pub fn rgbd_to_mesh<T: AsRef<Path>>(color_path: T, depth_path: T) -> (Vec<[f32; 3]>, Mesh) {
-    // Load the depth and color images
-    let color_image = image::open(color_path).expect("Failed to load color image");
-    let depth_image = image::open(depth_path).expect("Failed to load depth image");
+/// Points back-projected from an RGB-D image pair
+pub struct RgbdPoints {
+    pub positions: Vec<[f32; 3]>,          // Every valid pixel
+    pub colors: Vec<[f32; 4]>,             // Color of every valid pixel
+    pub filtered_positions: Vec<[f32; 3]>, // Subsample used for registration
+}
 
-    // Create a mesh for the points
-    let mut mesh = Mesh::new(
-        PrimitiveTopology::PointList,
-        RenderAssetUsages::default() // Use default asset usage
-    );
+fn open_image(path: &Path) -> Result<DynamicImage, RegistrationError> {
+    image::open(path).map_err(|err| match err {
+        ImageError::IoError(source) => RegistrationError::Io { path: path.to_path_buf(), source },
+        err => RegistrationError::UnsupportedImageFormat { path: path.to_path_buf(), reason: err.to_string() }
+    })
+}
+
+/// Back-projects every valid pixel, keeping one pixel out of `sample_step` in each direction for registration
+pub fn rgbd_to_points<T: AsRef<Path>>(color_path: T, depth_path: T, sample_step: u32) -> Result<RgbdPoints, RegistrationError> {
+    let (color_path, depth_path) = (color_path.as_ref(), depth_path.as_ref());
+
+    // Load the depth and color images
+    let DynamicImage::ImageRgb8(color_buffer) = open_image(color_path)? else {
+        return Err(RegistrationError::UnsupportedImageFormat {
+            path: color_path.to_path_buf(),
+            reason: "expected an 8-bit RGB image".to_string()
+        });
+    };
+    let DynamicImage::ImageLuma16(depth_buffer) = open_image(depth_path)? else {
+        return Err(RegistrationError::UnsupportedImageFormat {
+            path: depth_path.to_path_buf(),
+            reason: "expected a 16-bit grayscale image".to_string()
+        });
+    };
+    if color_buffer.dimensions() != depth_buffer.dimensions() {
+        return Err(RegistrationError::DimensionMismatch {
+            color: color_buffer.dimensions(),
+            depth: depth_buffer.dimensions()
+        });
+    }
 
     let mut positions = Vec::new();
     let mut filtered_positions = Vec::new();
     let mut colors = Vec::new();
 
-    if let DynamicImage::ImageRgb8(color_buffer) = color_image {
-        if let DynamicImage::ImageLuma16(depth_buffer) = depth_image {
-            let (width, height) = depth_buffer.dimensions();
-            for y in 0..height {
-                for x in 0..width {
-                    // Extract the depth value from the red channel (index 0)
-                    let depth_value = depth_buffer.get_pixel(x, y).channels()[0] as f32;
-
-                    if depth_value != NO_VALUE {
-                        let coordinates = compute_world_coordinates(x as f32, y as f32, depth_value);
-                        positions.push(coordinates);
-
-                        if x % 30 == 0 && y % 30 == 0  { // Filter some points
-                            filtered_positions.push(coordinates)
-                        }
-            
-                        // Get the corresponding color from the color image
-                        let color = color_buffer.get_pixel(x, y);
-                        colors.push([
-                            color.channels()[0] as f32 / 255.0,
-                            color.channels()[1] as f32 / 255.0,
-                            color.channels()[2] as f32 / 255.0,
-                            1.0, // Alpha channel
-                        ]);
-                    }
+    let (width, height) = depth_buffer.dimensions();
+    for y in 0..height {
+        for x in 0..width {
+            // Extract the depth value from the red channel (index 0)
+            let depth_value = depth_buffer.get_pixel(x, y).channels()[0] as f32;
+
+            if depth_value != NO_VALUE {
+                let coordinates = compute_world_coordinates(x as f32, y as f32, depth_value);
+                positions.push(coordinates);
+
+                if x % sample_step == 0 && y % sample_step == 0  { // Filter some points
+                    filtered_positions.push(coordinates)
                 }
+    
+                // Get the corresponding color from the color image
+                let color = color_buffer.get_pixel(x, y);
+                colors.push([
+                    color.channels()[0] as f32 / 255.0,
+                    color.channels()[1] as f32 / 255.0,
+                    color.channels()[2] as f32 / 255.0,
+                    1.0, // Alpha channel
+                ]);
             }
-        } else {
-            panic!("Expected an 16-bit grayscale image!");
         }
-    } else {
-        panic!("Expected an 8-bit RGB image!");
     }
 
-    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions.clone());
-    mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+    Ok(RgbdPoints { positions, colors, filtered_positions })
+}
+
+/// Point mesh of an RGB-D image pair, colored by the class of every point if a labeller is given
+pub fn rgbd_to_mesh<T: AsRef<Path>, F: Fn(&[[f32; 3]]) -> Vec<u32>>(
+    color_path: T,
+    depth_path: T,
+    sample_step: u32,
+    labeller: Option<F>
+) -> Result<(Vec<[f32; 3]>, Mesh), RegistrationError> {
+    let mut points = rgbd_to_points(color_path, depth_path, sample_step)?;
+    if let Some(labeller) = labeller {
+        points.colors = labeller(&points.positions).into_iter().map(label_color).collect();
+    }
+
+    // Create a mesh for the points
+    let mut mesh = Mesh::new(
+        PrimitiveTopology::PointList,
+        RenderAssetUsages::default() // Use default asset usage
+    );
+
+    println!("Mesh with {} points", points.positions.len());
 
-    println!("Mesh with {} points", positions.len());
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, points.positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, points.colors);
 
-    (filtered_positions, mesh)
+    Ok((points.filtered_positions, mesh))
+}
+
+// Distinct color of every class, gray for the unlabelled points
+fn label_color(label: u32) -> [f32; 4] {
+    if label == UNLABELLED {
+        return [0.5, 0.5, 0.5, 1.0];
+    }
+    // Hues spread by the golden angle, so that consecutive classes look different
+    let hue = (label as f32 * 137.508) % 360.0;
+    Color::hsl(hue, 0.8, 0.5).to_srgba().to_f32_array()
 }
 
 fn compute_world_coordinates(x: f32, y: f32, depth: f32) -> [f32; 3] {
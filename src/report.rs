@@ -0,0 +1,81 @@
+use std::fs::{self, File};
+use std::path::Path;
+use std::process::Command;
+use polars::prelude::*;
+use serde::Serialize;
+
+use crate::config::Experiment;
+
+/// Everything needed to trace a benchmark table back to the run that produced it
+#[derive(Debug, Serialize)]
+pub struct Metadata<'a> {
+    pub seed: u64,                  // Seed the runs derived their own from
+    pub git_commit: Option<String>, // Commit of the code, if run from a git checkout
+    pub experiment: &'a Experiment, // Input frames, poses and solver parameters
+}
+
+impl<'a> Metadata<'a> {
+    pub fn new(experiment: &'a Experiment, seed: u64) -> Self {
+        Metadata { seed, git_commit: git_commit(), experiment }
+    }
+}
+
+// Commit of the working directory, with a suffix if it has uncommitted changes
+fn git_commit() -> Option<String> {
+    let output = Command::new("git").args(["rev-parse", "HEAD"]).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let commit = String::from_utf8(output.stdout).ok()?.trim().to_string();
+
+    let dirty = Command::new("git")
+        .args(["status", "--porcelain", "--untracked-files=no"])
+        .output()
+        .is_ok_and(|status| !status.stdout.is_empty());
+    Some(if dirty { commit + "-dirty" } else { commit })
+}
+
+/// Writes the per-run and the aggregated results, as CSV, Parquet and JSON, and their metadata
+/// into the output directory of the experiment, if one is configured
+pub fn write_benchmark(
+    experiment: &Experiment,
+    metadata: &Metadata,
+    runs: &mut DataFrame,
+    summary: &mut DataFrame
+) -> Result<(), String> {
+    let Some(dir) = &experiment.output else {
+        return Ok(());
+    };
+    fs::create_dir_all(dir).map_err(|err| format!("Failed to create '{}': {}", dir.display(), err))?;
+
+    write_frame(dir, "runs", runs)?;
+    write_frame(dir, "summary", summary)?;
+
+    let path = dir.join("metadata.json");
+    let contents = serde_json::to_string_pretty(metadata).map_err(|err| err.to_string())?;
+    fs::write(&path, contents).map_err(|err| format!("Failed to write '{}': {}", path.display(), err))
+}
+
+// Writes `<name>.csv`, `<name>.parquet` and `<name>.json`
+fn write_frame(dir: &Path, name: &str, df: &mut DataFrame) -> Result<(), String> {
+    let path = dir.join(format!("{}.csv", name));
+    CsvWriter::new(create(&path)?)
+        .finish(df)
+        .map_err(|err| format!("Failed to write '{}': {}", path.display(), err))?;
+
+    let path = dir.join(format!("{}.parquet", name));
+    ParquetWriter::new(create(&path)?)
+        .finish(df)
+        .map_err(|err| format!("Failed to write '{}': {}", path.display(), err))?;
+
+    // Array of records, one object per row
+    let path = dir.join(format!("{}.json", name));
+    JsonWriter::new(create(&path)?)
+        .with_json_format(JsonFormat::Json)
+        .finish(df)
+        .map_err(|err| format!("Failed to write '{}': {}", path.display(), err))
+}
+
+fn create(path: &Path) -> Result<File, String> {
+    File::create(path).map_err(|err| format!("Failed to create '{}': {}", path.display(), err))
+}
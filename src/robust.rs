@@ -0,0 +1,428 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use bevy::prelude::*;
+use kiddo::{KdTree, SquaredEuclidean};
+use nalgebra::{Point3, Vector3};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{build_kd_tree, estimate_normals};
+
+/// Robust loss applied to the distance of every correspondence. The threshold is a distance,
+/// below it every kernel behaves like the plain squared distance.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum RobustKernel {
+    #[default]
+    None,                          // Squared distance
+    Huber { threshold: f32 },      // Linear beyond the threshold
+    Tukey { threshold: f32 },      // Constant beyond the threshold, smooth
+    Cauchy { threshold: f32 },     // Logarithmic
+    Truncated { threshold: f32 },  // Constant beyond the threshold
+}
+
+impl RobustKernel {
+    /// Loss of a correspondence from its squared distance
+    pub fn loss(&self, squared_distance: f32) -> f32 {
+        match *self {
+            RobustKernel::None => squared_distance,
+            RobustKernel::Huber { threshold } => {
+                let distance = squared_distance.sqrt();
+                if distance <= threshold {
+                    squared_distance
+                } else {
+                    2.0 * threshold * distance - threshold * threshold
+                }
+            }
+            RobustKernel::Tukey { threshold } => {
+                let c2 = threshold * threshold;
+                if squared_distance <= c2 {
+                    c2 / 3.0 * (1.0 - (1.0 - squared_distance / c2).powi(3))
+                } else {
+                    c2 / 3.0
+                }
+            }
+            RobustKernel::Cauchy { threshold } => {
+                let c2 = threshold * threshold;
+                c2 * (1.0 + squared_distance / c2).ln()
+            }
+            RobustKernel::Truncated { threshold } => squared_distance.min(threshold * threshold),
+        }
+    }
+
+    /// Weight of a correspondence in iteratively reweighted least squares
+    pub fn weight(&self, squared_distance: f32) -> f32 {
+        match *self {
+            RobustKernel::None => 1.0,
+            RobustKernel::Huber { threshold } => {
+                let distance = squared_distance.sqrt();
+                if distance <= threshold { 1.0 } else { threshold / distance }
+            }
+            RobustKernel::Tukey { threshold } => {
+                let c2 = threshold * threshold;
+                if squared_distance <= c2 { (1.0 - squared_distance / c2).powi(2) } else { 0.0 }
+            }
+            RobustKernel::Cauchy { threshold } => 1.0 / (1.0 + squared_distance / (threshold * threshold)),
+            RobustKernel::Truncated { threshold } => {
+                if squared_distance <= threshold * threshold { 1.0 } else { 0.0 }
+            }
+        }
+    }
+}
+
+/// Discards the worst correspondences
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Trimming {
+    #[default]
+    None,
+    Percentage { percentage: f32 }, // Percentage of correspondences discarded, the farthest ones
+    Auto { min_overlap: f32 },      // Overlap estimated at every evaluation (trimmed ICP), at least min_overlap
+}
+
+// Penalty of small overlaps in the trimmed objective e(ξ) / ξ^(1 + λ) (Chetverikov et al., 2005)
+const OVERLAP_PENALTY: f32 = 2.0;
+
+/// How the correspondences between the source and the target are filtered and weighted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CorrespondenceOptions {
+    pub max_distance: Option<f32>,     // Correspondences farther apart are discarded
+    pub trimming: Trimming,
+    pub mutual: bool,                  // Keep only reciprocal nearest neighbours
+    pub max_normal_angle: Option<f32>, // Maximum angle between the normals of both points (radians)
+    pub normal_neighbours: usize,      // Neighbours used to estimate the normals
+    pub kernel: RobustKernel,
+    pub same_label: bool,              // Match only points of the same class, if the points are labelled
+    #[serde(skip)]
+    pub labels: Option<Arc<PointLabels>>, // Set when the experiment has labels
+}
+
+impl Default for CorrespondenceOptions {
+    fn default() -> Self {
+        Self {
+            max_distance: None,
+            trimming: Trimming::None,
+            mutual: false,
+            max_normal_angle: None,
+            normal_neighbours: 10,
+            kernel: RobustKernel::None,
+            same_label: false,
+            labels: None,
+        }
+    }
+}
+
+impl CorrespondenceOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_distance.is_some_and(|distance| distance <= 0.0) {
+            return Err("max_distance must be positive".to_string());
+        }
+        if let Trimming::Percentage { percentage } = self.trimming {
+            if !(0.0..100.0).contains(&percentage) {
+                return Err("trimming percentage must be in [0, 100)".to_string());
+            }
+        }
+        if let Trimming::Auto { min_overlap } = self.trimming {
+            if !(min_overlap > 0.0 && min_overlap <= 1.0) {
+                return Err("trimming min_overlap must be in (0, 1]".to_string());
+            }
+        }
+        if self.max_normal_angle.is_some() && self.normal_neighbours < 3 {
+            return Err("normal_neighbours must be at least 3".to_string());
+        }
+        match self.kernel {
+            RobustKernel::Huber { threshold }
+            | RobustKernel::Tukey { threshold }
+            | RobustKernel::Cauchy { threshold }
+            | RobustKernel::Truncated { threshold } if threshold <= 0.0 =>
+                Err("kernel threshold must be positive".to_string()),
+            _ => Ok(())
+        }
+    }
+}
+
+/// Object class of every source and target point
+#[derive(Debug, Clone, Default)]
+pub struct PointLabels {
+    pub source: Vec<u32>,
+    pub target: Vec<u32>,
+}
+
+/// Source point matched with its closest target point
+#[derive(Debug, Clone, Copy)]
+pub struct Correspondence {
+    pub source: usize,         // Index of the source point
+    pub target: usize,         // Index of the target point
+    pub squared_distance: f32, // After transforming the source point
+}
+
+/// Finds the correspondences of the transformed source points in a fixed target and rejects the
+/// outliers. The target k-d tree, and the normals if needed, are computed only once.
+pub struct Matcher {
+    options: CorrespondenceOptions,
+    source: Vec<Vec3>,
+    target: Vec<Vec3>,
+    target_tree: KdTree<f32, 3>,
+    source_normals: Vec<Vec3>, // Empty unless normals are compared
+    target_normals: Vec<Vec3>,
+    source_labels: Vec<u32>,   // Empty unless only the points of the same class are matched
+    class_trees: HashMap<u32, KdTree<f32, 3>>, // Target points of every class
+}
+
+impl Matcher {
+    pub fn new(source: &[[f32; 3]], target: &[[f32; 3]], options: &CorrespondenceOptions) -> Self {
+        let target_points: Vec<Point3<f32>> = target.iter().map(|&p| Point3::from(p)).collect();
+        let target_tree = build_kd_tree(&target_points);
+
+        let (source_normals, target_normals) = if options.max_normal_angle.is_some() {
+            let source_points: Vec<Point3<f32>> = source.iter().map(|&p| Point3::from(p)).collect();
+            let source_tree = build_kd_tree(&source_points);
+            (
+                to_vec3(&estimate_normals(&source_points, &source_tree, options.normal_neighbours)),
+                to_vec3(&estimate_normals(&target_points, &target_tree, options.normal_neighbours))
+            )
+        } else {
+            (Vec::new(), Vec::new())
+        };
+
+        // One tree per class, so that the closest point of the same class is found
+        let (source_labels, class_trees) = match &options.labels {
+            Some(labels) if options.same_label => {
+                (labels.source.clone(), build_class_trees(target.iter().copied(), &labels.target))
+            }
+            _ => (Vec::new(), HashMap::new())
+        };
+
+        Matcher {
+            options: options.clone(),
+            source: source.iter().map(|&p| Vec3::from(p)).collect(),
+            target: target.iter().map(|&p| Vec3::from(p)).collect(),
+            target_tree,
+            source_normals,
+            target_normals,
+            source_labels,
+            class_trees,
+        }
+    }
+
+    pub fn kernel(&self) -> RobustKernel {
+        self.options.kernel
+    }
+
+    /// Normals of the target points from `normal_neighbours` neighbours, the ones of the normal
+    /// check when they were already estimated
+    pub fn target_normals(&self) -> Vec<Vector3<f32>> {
+        if !self.target_normals.is_empty() {
+            return self.target_normals.iter().map(|n| Vector3::new(n.x, n.y, n.z)).collect();
+        }
+        let target_points: Vec<Point3<f32>> = self.target.iter().map(|p| Point3::new(p.x, p.y, p.z)).collect();
+        estimate_normals(&target_points, &self.target_tree, self.options.normal_neighbours)
+    }
+
+    /// Correspondences of the source points moved by the transform that pass every rejection test
+    pub fn find(&self, transform: &Transform) -> Vec<Correspondence> {
+        let transformed: Vec<Vec3> = self.source.iter().map(|&src| transform.transform_point(src)).collect();
+
+        // Only needed to check that the target point has the source point as its closest point too,
+        // among the source points of its class if the classes are matched
+        let source_trees = self.options.mutual.then(|| {
+            if self.source_labels.is_empty() {
+                let points: Vec<Point3<f32>> = transformed.iter().map(|p| Point3::new(p.x, p.y, p.z)).collect();
+                HashMap::from([(ALL_CLASSES, build_kd_tree(&points))])
+            } else {
+                build_class_trees(transformed.iter().map(|p| p.to_array()), &self.source_labels)
+            }
+        });
+        let max_squared_distance = self.options.max_distance.map(|distance| distance * distance);
+        let min_cosine = self.options.max_normal_angle.map(f32::cos);
+
+        let mut correspondences: Vec<Correspondence> = transformed
+            .par_iter()
+            .enumerate()
+            .filter_map(|(index, point)| {
+                let tree = if self.source_labels.is_empty() {
+                    &self.target_tree
+                } else {
+                    self.class_trees.get(&self.source_labels[index])?
+                };
+                let nearest = tree.nearest_one::<SquaredEuclidean>(&point.to_array());
+                let correspondence = Correspondence {
+                    source: index,
+                    target: nearest.item as usize,
+                    squared_distance: nearest.distance,
+                };
+
+                if max_squared_distance.is_some_and(|max| correspondence.squared_distance > max) {
+                    return None;
+                }
+                if let Some(min_cosine) = min_cosine {
+                    let normal = transform.rotation * self.source_normals[index];
+                    if normal.dot(self.target_normals[correspondence.target]) < min_cosine {
+                        return None;
+                    }
+                }
+                if let Some(source_trees) = &source_trees {
+                    let class = self.source_labels.get(index).copied().unwrap_or(ALL_CLASSES);
+                    let target_point = self.target[correspondence.target].to_array();
+                    if source_trees[&class].nearest_one::<SquaredEuclidean>(&target_point).item as usize != index {
+                        return None;
+                    }
+                }
+                Some(correspondence)
+            })
+            .collect();
+
+        match self.options.trimming {
+            Trimming::None => {}
+            Trimming::Percentage { percentage } => {
+                let kept = ((correspondences.len() as f32) * (1.0 - percentage / 100.0)).ceil() as usize;
+                trim(&mut correspondences, kept);
+            }
+            Trimming::Auto { min_overlap } => {
+                correspondences.sort_unstable_by(|a, b| a.squared_distance.total_cmp(&b.squared_distance));
+                let kept = self.estimate_kept(&correspondences, min_overlap);
+                correspondences.truncate(kept);
+            }
+        }
+
+        correspondences
+    }
+
+    /// Fraction of the source points with an accepted correspondence
+    pub fn overlap(&self, correspondences: &[Correspondence]) -> f32 {
+        correspondences.len() as f32 / self.source.len() as f32
+    }
+
+    /// Mean robust loss of the correspondences, infinite if every one was rejected
+    pub fn score(&self, correspondences: &[Correspondence]) -> f32 {
+        self.loss(&correspondences.iter().map(|c| c.squared_distance).collect::<Vec<_>>())
+    }
+
+    /// Mean robust loss of the squared distances of the accepted correspondences. With automatic
+    /// trimming it is divided by the overlap, so that discarding points is not rewarded by itself.
+    pub fn loss(&self, squared_distances: &[f32]) -> f32 {
+        if squared_distances.is_empty() {
+            return f32::INFINITY;
+        }
+        let mean = squared_distances
+            .iter()
+            .map(|&d| self.options.kernel.loss(d))
+            .sum::<f32>() / squared_distances.len() as f32;
+
+        match self.options.trimming {
+            Trimming::Auto { .. } => {
+                let overlap = squared_distances.len() as f32 / self.source.len() as f32;
+                mean / overlap.powf(1.0 + OVERLAP_PENALTY)
+            }
+            _ => mean
+        }
+    }
+
+    // Number of correspondences, sorted by distance, that minimizes the trimmed objective
+    fn estimate_kept(&self, sorted: &[Correspondence], min_overlap: f32) -> usize {
+        let total = self.source.len() as f32;
+        let min_kept = ((min_overlap * total).ceil() as usize).clamp(1, sorted.len().max(1));
+
+        let mut best = (sorted.len(), f32::INFINITY);
+        let mut sum = 0.0;
+        for (i, correspondence) in sorted.iter().enumerate() {
+            sum += correspondence.squared_distance;
+            let kept = i + 1;
+            if kept < min_kept {
+                continue;
+            }
+            let overlap = kept as f32 / total;
+            let objective = sum / kept as f32 / overlap.powf(1.0 + OVERLAP_PENALTY);
+            if objective < best.1 {
+                best = (kept, objective);
+            }
+        }
+        best.0
+    }
+}
+
+// Key of the single tree of the mutual check when the classes are not matched
+const ALL_CLASSES: u32 = u32::MAX;
+
+// One k-d tree per class, whose items are the indices of the points among all the classes
+fn build_class_trees(points: impl Iterator<Item = [f32; 3]>, labels: &[u32]) -> HashMap<u32, KdTree<f32, 3>> {
+    let mut trees: HashMap<u32, KdTree<f32, 3>> = HashMap::new();
+    for (index, (point, label)) in points.zip(labels.iter()).enumerate() {
+        trees.entry(*label).or_default().add(&point, index as u64);
+    }
+    trees
+}
+
+// Keeps the given number of closest correspondences
+fn trim(correspondences: &mut Vec<Correspondence>, kept: usize) {
+    if kept == 0 {
+        correspondences.clear();
+    } else if kept < correspondences.len() {
+        correspondences.select_nth_unstable_by(kept - 1, |a, b| a.squared_distance.total_cmp(&b.squared_distance));
+        correspondences.truncate(kept);
+    }
+}
+
+fn to_vec3(vectors: &[Vector3<f32>]) -> Vec<Vec3> {
+    vectors.iter().map(|v| Vec3::new(v.x, v.y, v.z)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-5, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn kernels_at_zero_threshold_and_beyond() {
+        // Squared distances 0, at the threshold of 2 and at twice the threshold
+        let squared_distances = [0.0, 4.0, 16.0];
+        let cases = [
+            (RobustKernel::None, [0.0, 4.0, 16.0], [1.0, 1.0, 1.0]),
+            (RobustKernel::Huber { threshold: 2.0 }, [0.0, 4.0, 12.0], [1.0, 1.0, 0.5]),
+            (RobustKernel::Tukey { threshold: 2.0 }, [0.0, 4.0 / 3.0, 4.0 / 3.0], [1.0, 0.0, 0.0]),
+            (RobustKernel::Cauchy { threshold: 2.0 }, [0.0, 4.0 * 2f32.ln(), 4.0 * 5f32.ln()], [1.0, 0.5, 0.2]),
+            (RobustKernel::Truncated { threshold: 2.0 }, [0.0, 4.0, 4.0], [1.0, 1.0, 0.0]),
+        ];
+        for (kernel, losses, weights) in cases {
+            for ((&squared_distance, loss), weight) in squared_distances.iter().zip(losses).zip(weights) {
+                assert_close(kernel.loss(squared_distance), loss);
+                assert_close(kernel.weight(squared_distance), weight);
+            }
+        }
+    }
+
+    // Source points above target points far apart from each other, at the given distances
+    fn kept(distances: &[f32], trimming: Trimming) -> Vec<usize> {
+        let target: Vec<[f32; 3]> = (0..distances.len()).map(|i| [i as f32 * 100.0, 0.0, 0.0]).collect();
+        let source: Vec<[f32; 3]> = target.iter().zip(distances).map(|(p, d)| [p[0], *d, 0.0]).collect();
+        let options = CorrespondenceOptions { trimming, ..Default::default() };
+        let mut kept: Vec<usize> = Matcher::new(&source, &target, &options)
+            .find(&Transform::IDENTITY)
+            .iter()
+            .map(|c| c.source)
+            .collect();
+        kept.sort_unstable();
+        kept
+    }
+
+    #[test]
+    fn percentage_trimming_keeps_the_closest() {
+        let distances = [0.9, 0.1, 0.5, 0.3, 0.7, 0.2, 0.8, 0.4, 0.6, 1.0];
+        assert_eq!(kept(&distances, Trimming::Percentage { percentage: 30.0 }), vec![1, 2, 3, 4, 5, 7, 8]);
+        // The number of kept correspondences is rounded up, 5% of 10 discards none
+        assert_eq!(kept(&distances, Trimming::Percentage { percentage: 5.0 }).len(), 10);
+        assert_eq!(kept(&distances, Trimming::None).len(), 10);
+    }
+
+    #[test]
+    fn auto_trimming_discards_the_outliers() {
+        let distances = [0.01, 0.01, 5.0, 0.01, 0.01, 0.01, 0.01, 5.0, 0.01, 0.01];
+        assert_eq!(kept(&distances, Trimming::Auto { min_overlap: 0.5 }), vec![0, 1, 3, 4, 5, 6, 8, 9]);
+        // Never fewer than the minimum overlap, even if outliers are kept
+        assert_eq!(kept(&distances, Trimming::Auto { min_overlap: 0.9 }).len(), 9);
+    }
+}
@@ -1,5 +1,276 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use bevy::math::Vec3;
+use bevy::prelude::Transform;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::error::RegistrationError;
+use crate::robust::{CorrespondenceOptions, PointLabels};
+use icp::{IterativeClosestPoint, PointToPlaneIcp};
+use gicp::GeneralizedIcp;
+use ga::GeneticAlgorithm;
+use es::EvolutionStrategy;
+use cmaes::CovarianceMatrixAdaptation;
+use pso::ParticleSwarmOptimization;
+use de::DifferentialEvolution;
+use pose::PoseVector;
+
+pub mod pose;
 pub mod icp;
+pub mod gicp;
 pub mod ga;
 pub mod es;
+pub mod cmaes;
 pub mod pso;
-pub mod de;
\ No newline at end of file
+pub mod de;
+pub mod random;
+
+/// Reason why a solver stopped
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Termination {
+    Converged,     // The fitness fell below the convergence threshold
+    Stagnated,     // The fitness stopped improving
+    MaxIterations  // The iteration budget was exhausted
+}
+
+/// Outcome of a registration
+#[derive(Debug, Clone)]
+pub struct RegistrationResult {
+    pub transform: Transform,      // Transform that aligns the source with the target
+    pub fitness: f32,              // Fitness of the transform
+    pub iterations: usize,         // Iterations or generations run
+    pub evaluations: usize,        // Number of fitness evaluations
+    pub termination: Termination,
+    pub overlap: f32,              // Fraction of the source points with an accepted correspondence
+    pub seed: Option<u64>,         // Seed that replays the run, None for the deterministic solvers
+    pub history: Vec<f32>,         // Best fitness after every iteration
+}
+
+/// Starting pose of a registration and the region searched around it
+#[derive(Debug, Clone, Copy)]
+pub struct InitialGuess {
+    pub pose: Transform,
+    pub translation_radius: f32, // Maximum distance from the initial translation
+    pub rotation_radius: f32,    // Maximum angle from the initial rotation (radians)
+}
+
+impl InitialGuess {
+    /// Draws a random pose inside the search region
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> Transform {
+        self.transform_at(&self.sample_offset(rng))
+    }
+
+    /// Draws a random offset inside the search region, as accepted by `transform_at`
+    pub fn sample_offset<R: Rng>(&self, rng: &mut R) -> PoseVector {
+        PoseVector::new(
+            random_unit_vector(rng) * rng.gen::<f32>().cbrt(),
+            random_unit_vector(rng) * rng.gen_range(0.0..=1.0)
+        )
+    }
+
+    /// Pose at an offset from the initial pose, relative to the search radii, so that the search
+    /// region is the unit ball in both the translation and the rotation vector
+    pub fn transform_at(&self, offset: &PoseVector) -> Transform {
+        offset.scale(self.translation_radius, self.rotation_radius).apply(&self.pose)
+    }
+}
+
+// Uniformly distributed direction
+fn random_unit_vector<R: Rng>(rng: &mut R) -> Vec3 {
+    loop {
+        let v = Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0));
+        let length_squared = v.length_squared();
+        if length_squared > 1e-6 && length_squared <= 1.0 {
+            return v / length_squared.sqrt();
+        }
+    }
+}
+
+/// Common interface of the registration algorithms
+pub trait Registrar {
+    /// Short name used in logs and reports
+    fn name(&self) -> &str;
+
+    /// Finds the transform that aligns the source points with the target points,
+    /// searching around the initial guess
+    fn register(
+        &self,
+        source: &[[f32; 3]],
+        target: &[[f32; 3]],
+        initial: &InitialGuess
+    ) -> Result<RegistrationResult, RegistrationError>;
+}
+
+/// Solver together with its parameters, as listed in an experiment file.
+///
+/// The stochastic solvers (GA, ES, CMAES, PSO and DE) draw every random number from streams derived
+/// from their `seed`, and pick a random seed when none is given. The seed used is reported in the
+/// result, so that any run can be replayed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "solver")]
+pub enum Solver {
+    ICP(IterativeClosestPoint),
+    PlaneICP(PointToPlaneIcp),
+    GICP(GeneralizedIcp),
+    GA(GeneticAlgorithm),
+    ES(EvolutionStrategy),
+    CMAES(CovarianceMatrixAdaptation),
+    PSO(ParticleSwarmOptimization),
+    DE(DifferentialEvolution)
+}
+
+impl Solver {
+    pub fn registrar(&self) -> &dyn Registrar {
+        match self {
+            Solver::ICP(solver) => solver,
+            Solver::PlaneICP(solver) => solver,
+            Solver::GICP(solver) => solver,
+            Solver::GA(solver) => solver,
+            Solver::ES(solver) => solver,
+            Solver::CMAES(solver) => solver,
+            Solver::PSO(solver) => solver,
+            Solver::DE(solver) => solver
+        }
+    }
+
+    /// Checks the parameters that can not be used as given
+    pub fn validate(&self) -> Result<(), String> {
+        self.correspondences().validate()?;
+        match self {
+            Solver::PlaneICP(solver) => solver.validate(),
+            Solver::GICP(solver) => solver.validate(),
+            Solver::GA(solver) => solver.validate(),
+            Solver::ES(solver) => solver.validate(),
+            Solver::CMAES(solver) => solver.validate(),
+            Solver::PSO(solver) => solver.validate(),
+            Solver::DE(solver) => solver.validate(),
+            _ => Ok(())
+        }
+    }
+
+    /// Outlier rejection and robust loss of the correspondences
+    pub fn correspondences(&self) -> &CorrespondenceOptions {
+        match self {
+            Solver::ICP(solver) => &solver.correspondences,
+            Solver::PlaneICP(solver) => &solver.correspondences,
+            Solver::GICP(solver) => &solver.correspondences,
+            Solver::GA(solver) => &solver.correspondences,
+            Solver::ES(solver) => &solver.correspondences,
+            Solver::CMAES(solver) => &solver.correspondences,
+            Solver::PSO(solver) => &solver.correspondences,
+            Solver::DE(solver) => &solver.correspondences
+        }
+    }
+
+    fn correspondences_mut(&mut self) -> &mut CorrespondenceOptions {
+        match self {
+            Solver::ICP(solver) => &mut solver.correspondences,
+            Solver::PlaneICP(solver) => &mut solver.correspondences,
+            Solver::GICP(solver) => &mut solver.correspondences,
+            Solver::GA(solver) => &mut solver.correspondences,
+            Solver::ES(solver) => &mut solver.correspondences,
+            Solver::CMAES(solver) => &mut solver.correspondences,
+            Solver::PSO(solver) => &mut solver.correspondences,
+            Solver::DE(solver) => &mut solver.correspondences
+        }
+    }
+
+    /// Classes of the points to register, used by the correspondences with `same_label`
+    pub fn set_labels(&mut self, labels: Arc<PointLabels>) {
+        self.correspondences_mut().labels = Some(labels);
+    }
+
+    /// Sets the seed of the stochastic solvers, the deterministic ones ignore it
+    pub fn set_seed(&mut self, seed: u64) {
+        match self {
+            Solver::GA(solver) => solver.seed = Some(seed),
+            Solver::ES(solver) => solver.seed = Some(seed),
+            Solver::CMAES(solver) => solver.seed = Some(seed),
+            Solver::PSO(solver) => solver.seed = Some(seed),
+            Solver::DE(solver) => solver.seed = Some(seed),
+            _ => {}
+        }
+    }
+
+    pub fn set_verbose(&mut self, verbose: bool) {
+        match self {
+            Solver::ICP(solver) => solver.verbose = verbose,
+            Solver::PlaneICP(solver) => solver.verbose = verbose,
+            Solver::GICP(solver) => solver.verbose = verbose,
+            Solver::GA(solver) => solver.verbose = verbose,
+            Solver::ES(solver) => solver.verbose = verbose,
+            Solver::CMAES(solver) => solver.verbose = verbose,
+            Solver::PSO(solver) => solver.verbose = verbose,
+            Solver::DE(solver) => solver.verbose = verbose
+        }
+    }
+}
+
+impl FromStr for Solver {
+    type Err = String;
+
+    /// Solver with its default parameters
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_uppercase().as_str() {
+            "ICP" => Ok(Solver::ICP(Default::default())),
+            "PLANEICP" => Ok(Solver::PlaneICP(Default::default())),
+            "GICP" => Ok(Solver::GICP(Default::default())),
+            "GA" => Ok(Solver::GA(Default::default())),
+            "ES" => Ok(Solver::ES(Default::default())),
+            "CMAES" | "CMA-ES" => Ok(Solver::CMAES(Default::default())),
+            "PSO" => Ok(Solver::PSO(Default::default())),
+            "DE" => Ok(Solver::DE(Default::default())),
+            _ => Err(format!("Unknown solver '{}' (expected ICP, PlaneICP, GICP, GA, ES, CMAES, PSO or DE)", name))
+        }
+    }
+}
+
+/// Point clouds shared by the tests of the solvers
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use bevy::prelude::*;
+    use super::{pose, InitialGuess, RegistrationResult};
+
+    /// Inside corner of a box, three orthogonal faces that constrain every degree of freedom
+    pub fn corner() -> Vec<[f32; 3]> {
+        let mut points = Vec::new();
+        for face in 0..3 {
+            for i in 1..10 {
+                for j in 1..10 {
+                    let mut point = [0.0; 3];
+                    point[(face + 1) % 3] = i as f32 * 0.1;
+                    point[(face + 2) % 3] = j as f32 * 0.1;
+                    points.push(point);
+                }
+            }
+        }
+        points
+    }
+
+    /// Small motion from the source to the target
+    pub fn motion() -> Transform {
+        Transform::from_xyz(0.03, -0.02, 0.04).with_rotation(Quat::from_scaled_axis(Vec3::new(0.03, -0.05, 0.04)))
+    }
+
+    /// Corner as the source, and moved by the motion as the target
+    pub fn clouds() -> (Vec<[f32; 3]>, Vec<[f32; 3]>) {
+        let source = corner();
+        let target = source.iter().map(|&p| motion().transform_point(Vec3::from(p)).to_array()).collect();
+        (source, target)
+    }
+
+    /// Search region around the identity that contains the motion
+    pub fn initial_guess() -> InitialGuess {
+        InitialGuess { pose: Transform::IDENTITY, translation_radius: 0.1, rotation_radius: 0.2 }
+    }
+
+    /// Checks that the result is within the given translation and angle (radians) of the motion
+    pub fn assert_recovers_motion(result: &RegistrationResult, translation: f32, rotation: f32) {
+        let (translation_error, rotation_error) = pose::distance(&result.transform, &motion());
+        assert!(
+            translation_error < translation && rotation_error < rotation,
+            "{:?} is {} and {} rad from the motion", result.transform, translation_error, rotation_error
+        );
+    }
+}
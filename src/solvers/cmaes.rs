@@ -0,0 +1,237 @@
+use nalgebra::{SMatrix, SVector};
+use rand_distr::StandardNormal;
+use rand::Rng;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::FitnessContext;
+use crate::error::RegistrationError;
+use crate::robust::CorrespondenceOptions;
+use super::random::RandomStreams;
+use super::{InitialGuess, Registrar, RegistrationResult, Termination};
+
+// Dimension of the search space: translation and rotation vector
+const N: usize = 6;
+
+type Vector = SVector<f32, N>;
+type Matrix = SMatrix<f32, N, N>;
+
+/// CMA-ES parameters
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CovarianceMatrixAdaptation {
+    pub population_size: usize,
+    pub generations: usize,
+    pub initial_step_size: f32, // Relative to the search radii of the initial guess
+    pub min_step_size: f32,     // The search has stagnated below this step size
+    pub convergence_threshold: f32,
+    pub correspondences: CorrespondenceOptions,
+    pub seed: Option<u64>,
+    pub verbose: bool,
+}
+
+impl Default for CovarianceMatrixAdaptation {
+    fn default() -> Self {
+        Self {
+            population_size: 10,
+            generations: 1000,
+            initial_step_size: 0.3,
+            min_step_size: 1e-5,
+            convergence_threshold: 0.5,
+            correspondences: CorrespondenceOptions::default(),
+            seed: None,
+            verbose: false,
+        }
+    }
+}
+
+impl CovarianceMatrixAdaptation {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.population_size < 2 {
+            return Err("the population needs at least 2 individuals".to_string());
+        }
+        if self.initial_step_size <= 0.0 {
+            return Err("initial_step_size must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Registrar for CovarianceMatrixAdaptation {
+    fn name(&self) -> &str {
+        "CMA-ES"
+    }
+
+    fn register(
+        &self,
+        source: &[[f32; 3]],
+        target: &[[f32; 3]],
+        initial: &InitialGuess
+    ) -> Result<RegistrationResult, RegistrationError> {
+        if source.is_empty() || target.is_empty() {
+            return Err(RegistrationError::EmptyPointCloud);
+        }
+
+        let context = FitnessContext::with_options(source, target, &self.correspondences);
+
+        // Recombination weights of the best half of the population
+        let lambda = self.population_size;
+        let mu = lambda / 2;
+        let weights: Vec<f32> = (0..mu)
+            .map(|i| (mu as f32 + 0.5).ln() - (i as f32 + 1.0).ln())
+            .collect();
+        let weights_sum: f32 = weights.iter().sum();
+        let weights: Vec<f32> = weights.iter().map(|w| w / weights_sum).collect();
+        let mu_eff = 1.0 / weights.iter().map(|w| w * w).sum::<f32>();
+
+        // Learning rates of the evolution paths, the covariance matrix and the step size (Hansen, 2016)
+        let n = N as f32;
+        let cc = (4.0 + mu_eff / n) / (n + 4.0 + 2.0 * mu_eff / n);
+        let cs = (mu_eff + 2.0) / (n + mu_eff + 5.0);
+        let c1 = 2.0 / ((n + 1.3).powi(2) + mu_eff);
+        let cmu = (1.0 - c1).min(2.0 * (mu_eff - 2.0 + 1.0 / mu_eff) / ((n + 2.0).powi(2) + mu_eff));
+        let damps = 1.0 + 2.0 * (((mu_eff - 1.0) / (n + 1.0)).sqrt() - 1.0).max(0.0) + cs;
+        let chi_n = n.sqrt() * (1.0 - 1.0 / (4.0 * n) + 1.0 / (21.0 * n * n));
+
+        // The search starts at the initial guess, in coordinates relative to the search radii
+        let mut mean = Vector::zeros();
+        let mut sigma = self.initial_step_size;
+        let mut covariance = Matrix::identity();
+        let mut path_c = Vector::zeros();
+        let mut path_sigma = Vector::zeros();
+
+        let mut best_transform = initial.pose;
+        let mut best_fitness = context.evaluate(&best_transform);
+        let mut history = Vec::new();
+        let mut termination = Termination::MaxIterations;
+
+        // One random stream per offspring, independent of the thread that evaluates it
+        let streams = RandomStreams::new(self.seed);
+
+        for g in 0..self.generations {
+            // C = B D² Bᵀ
+            let eigen = covariance.symmetric_eigen();
+            let basis = eigen.eigenvectors;
+            let scales = eigen.eigenvalues.map(|value| value.max(1e-20).sqrt());
+
+            // Sample and evaluate the offspring: x = m + σ B D z
+            let mut offspring: Vec<(Vector, f32)> = (0..lambda)
+                .into_par_iter()
+                .map(|i| {
+                    let mut rng = streams.stream(g, i);
+                    let z = Vector::from_fn(|_, _| rng.sample::<f32, _>(StandardNormal));
+                    let y = basis * z.component_mul(&scales);
+                    let fitness = context.evaluate(&initial.transform_at(&(mean + y * sigma).into()));
+                    (y, fitness)
+                })
+                .collect();
+            offspring.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+            if offspring[0].1 < best_fitness {
+                best_fitness = offspring[0].1;
+                best_transform = initial.transform_at(&(mean + offspring[0].0 * sigma).into());
+            }
+
+            // Move the mean towards the weighted best offspring
+            let y_w: Vector = offspring.iter().zip(weights.iter()).map(|((y, _), w)| y * *w).sum();
+            mean += y_w * sigma;
+
+            // Cumulative step-size adaptation, using C^(-1/2) = B D⁻¹ Bᵀ
+            let inverse_sqrt = basis * Matrix::from_diagonal(&scales.map(|s| 1.0 / s)) * basis.transpose();
+            path_sigma = path_sigma * (1.0 - cs) + inverse_sqrt * y_w * (cs * (2.0 - cs) * mu_eff).sqrt();
+            let generation_factor = (1.0 - (1.0 - cs).powi(2 * (g as i32 + 1))).sqrt();
+            let h_sigma = path_sigma.norm() / generation_factor / chi_n < 1.4 + 2.0 / (n + 1.0);
+
+            // Covariance matrix adaptation: rank-one update from the evolution path, rank-mu from the offspring
+            path_c *= 1.0 - cc;
+            if h_sigma {
+                path_c += y_w * (cc * (2.0 - cc) * mu_eff).sqrt();
+            }
+            let rank_mu: Matrix = offspring
+                .iter()
+                .zip(weights.iter())
+                .map(|((y, _), w)| y * y.transpose() * *w)
+                .sum();
+            let correction = if h_sigma { 0.0 } else { cc * (2.0 - cc) };
+            covariance = covariance * (1.0 - c1 - cmu)
+                + (path_c * path_c.transpose() + covariance * correction) * c1
+                + rank_mu * cmu;
+            covariance = (covariance + covariance.transpose()) * 0.5;
+
+            sigma *= ((cs / damps) * (path_sigma.norm() / chi_n - 1.0)).exp();
+
+            history.push(best_fitness);
+
+            if self.verbose { println!("Generation {} | Best fitness: {} | Step size: {}", g, best_fitness, sigma); }
+
+            // Check convergence
+            if best_fitness < self.convergence_threshold {
+                termination = Termination::Converged;
+                break;
+            }
+            if sigma * scales.max() < self.min_step_size || !sigma.is_finite() {
+                termination = Termination::Stagnated;
+                break;
+            }
+        }
+
+        if !best_fitness.is_finite() {
+            return Err(RegistrationError::NotConverged { solver: self.name().to_string() });
+        }
+
+        Ok(RegistrationResult {
+            transform: best_transform,
+            fitness: best_fitness,
+            iterations: history.len(),
+            evaluations: context.evaluations(),
+            termination,
+            overlap: context.overlap(&best_transform),
+            seed: Some(streams.seed()),
+            history
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::solvers::fixtures::{assert_recovers_motion, clouds, initial_guess};
+
+    fn cmaes() -> CovarianceMatrixAdaptation {
+        CovarianceMatrixAdaptation { generations: 300, convergence_threshold: 1e-6, seed: Some(1), ..Default::default() }
+    }
+
+    #[test]
+    fn recovers_a_small_motion() {
+        let (source, target) = clouds();
+        let result = cmaes().register(&source, &target, &initial_guess()).unwrap();
+        assert_recovers_motion(&result, 5e-3, 1e-2);
+        assert_eq!(result.seed, Some(1));
+    }
+
+    #[test]
+    fn is_reproducible_from_the_seed() {
+        let (source, target) = clouds();
+        let cmaes = CovarianceMatrixAdaptation { generations: 10, ..cmaes() };
+        let first = cmaes.register(&source, &target, &initial_guess()).unwrap();
+        let second = cmaes.register(&source, &target, &initial_guess()).unwrap();
+        assert_eq!(first.transform, second.transform);
+        assert_eq!(first.history, second.history);
+    }
+
+    #[test]
+    fn fitness_never_increases() {
+        let (source, target) = clouds();
+        let result = CovarianceMatrixAdaptation { generations: 50, ..cmaes() }
+            .register(&source, &target, &initial_guess())
+            .unwrap();
+        assert!(result.history.windows(2).all(|pair| pair[1] <= pair[0]));
+    }
+
+    #[test]
+    fn rejects_a_population_below_two() {
+        assert!(CovarianceMatrixAdaptation::default().validate().is_ok());
+        assert!(CovarianceMatrixAdaptation { population_size: 1, ..Default::default() }.validate().is_err());
+        assert!(CovarianceMatrixAdaptation { initial_step_size: 0.0, ..Default::default() }.validate().is_err());
+    }
+}
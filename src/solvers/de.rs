@@ -1,156 +1,641 @@
 use rand::prelude::*;
-use bevy::math::{Quat, Vec3};
+use rand_distr::StandardNormal;
 use bevy::prelude::Transform;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::utils::fitness;
-
-pub fn differential_evolution(
-    source: &Vec<[f32; 3]>,
-    target: &Vec<[f32; 3]>,
-    population_size: usize,
-    generations: usize,
-    crossover_probability: f32,
-    scale_factor: f32,
-    convergence_threshold: f32,
-    verbose: bool
-) -> Result<Transform, String> {
-    if source.is_empty() || target.is_empty() {
-        return Err("Source or target point cloud is empty.".to_string());
-    }
-
-    let mut rng = thread_rng();
-
-    // Preprocess points into Vec3 for easier calculations
-    let source_points: Vec<Vec3> = source.iter().map(|&p| Vec3::from(p)).collect();
-    let target_points: Vec<Vec3> = target.iter().map(|&p| Vec3::from(p)).collect();
-
-    // Individual representation: Transform (translation + rotation)
-    struct Individual {
-        transform: Transform,
-        fitness: f32,
-    }
-
-    // Initialize the population
-    let mut population: Vec<Individual> = (0..population_size)
-        .into_par_iter()
-        .map(|_| {
-            let mut rng = rand::thread_rng();
-            let transform = Transform {
-                translation: Vec3::new(
-                    rng.gen_range(-1.0..1.0),
-                    rng.gen_range(-1.0..1.0),
-                    rng.gen_range(-1.0..1.0),
-                ),
-                rotation: Quat::from_euler(
-                    bevy::math::EulerRot::XYZ,
-                    rng.gen_range(-std::f32::consts::PI..std::f32::consts::PI),
-                    rng.gen_range(-std::f32::consts::PI..std::f32::consts::PI),
-                    rng.gen_range(-std::f32::consts::PI..std::f32::consts::PI),
-                ),
-                ..Default::default()
-            };
-
-            let fitness_value = fitness(&transform, &source_points, &target_points);
-
-            Individual {
-                transform,
-                fitness: fitness_value,
+use crate::utils::FitnessContext;
+use crate::error::RegistrationError;
+use crate::robust::CorrespondenceOptions;
+use super::pose::PoseVector;
+use super::random::RandomStreams;
+use super::{InitialGuess, Registrar, RegistrationResult, Termination};
+
+/// Individuals and differences the mutant is built from
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Strategy {
+    Rand1,          // a + F (b - c)
+    Best1,          // best + F (a - b)
+    CurrentToBest1, // x + F (best - x) + F (a - b)
+    Rand2,          // a + F (b - c) + F (d - e)
+}
+
+/// How the trial takes its parameters from the mutant
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Crossover {
+    Binomial,    // Every parameter independently, with the crossover probability
+    Exponential, // A run of consecutive parameters, continued with the crossover probability
+}
+
+/// Self-adaptation of the scale factor and the crossover probability. The adaptive variants use the
+/// current-to-pbest/1 strategy with an archive of replaced parents, whatever the chosen strategy.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Adaptation {
+    #[default]
+    None,
+    #[serde(rename = "JADE")]
+    Jade { learning_rate: f32, p_best: f32 }, // Zhang & Sanderson, 2009
+    #[serde(rename = "SHADE")]
+    Shade { memory_size: usize, p_best: f32 }, // Tanabe & Fukunaga, 2013
+}
+
+/// When the trials replace their parents
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Mode {
+    Asynchronous, // One trial at a time, a better trial is used by the next ones of the same generation
+    Synchronous,  // Every trial of the generation is built first, then evaluated in parallel
+}
+
+/// Differential evolution parameters
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DifferentialEvolution {
+    pub population_size: usize,
+    pub generations: usize,
+    pub crossover_probability: f32,
+    pub scale_factor: f32,
+    pub strategy: Strategy,
+    pub crossover: Crossover,
+    pub adaptation: Adaptation,
+    pub mode: Mode,
+    pub convergence_threshold: f32,
+    pub correspondences: CorrespondenceOptions,
+    pub seed: Option<u64>,
+    pub verbose: bool,
+}
+
+impl Default for DifferentialEvolution {
+    fn default() -> Self {
+        Self {
+            population_size: 100,
+            generations: 100,
+            crossover_probability: 0.5,
+            scale_factor: 0.5,
+            strategy: Strategy::Rand1,
+            crossover: Crossover::Binomial,
+            adaptation: Adaptation::None,
+            mode: Mode::Asynchronous,
+            convergence_threshold: 0.5,
+            correspondences: CorrespondenceOptions::default(),
+            seed: None,
+            verbose: false,
+        }
+    }
+}
+
+impl DifferentialEvolution {
+    pub fn validate(&self) -> Result<(), String> {
+        // The current individual and the distinct individuals of the mutation
+        let needed = match (self.adaptation, self.strategy) {
+            (Adaptation::None, Strategy::Rand2) => 6,
+            (Adaptation::None, _) => 4,
+            _ => 3
+        };
+        if self.population_size < needed {
+            return Err(format!("the DE strategy needs a population of at least {}", needed));
+        }
+        match self.adaptation {
+            Adaptation::Jade { learning_rate, p_best }
+                if !(0.0..=1.0).contains(&learning_rate) || p_best <= 0.0 || p_best > 1.0 =>
+                Err("JADE needs a learning_rate in [0, 1] and a p_best in (0, 1]".to_string()),
+            Adaptation::Shade { memory_size, p_best } if memory_size == 0 || p_best <= 0.0 || p_best > 1.0 =>
+                Err("SHADE needs a memory_size of at least 1 and a p_best in (0, 1]".to_string()),
+            _ => Ok(())
+        }
+    }
+}
+
+// Difference `to - from` of two poses
+fn difference(to: &Transform, from: &Transform) -> PoseVector {
+    PoseVector::between(from, to)
+}
+
+// Distinct random indices of the population, different from the excluded ones
+fn pick<R: Rng>(rng: &mut R, population_size: usize, excluded: &[usize], count: usize) -> Vec<usize> {
+    let mut indices: Vec<usize> = Vec::with_capacity(count);
+    while indices.len() < count {
+        let index = rng.gen_range(0..population_size);
+        if !excluded.contains(&index) && !indices.contains(&index) {
+            indices.push(index);
+        }
+    }
+    indices
+}
+
+// Redraws of a scale factor that is not positive before falling back to the location
+const MAX_REDRAWS: usize = 100;
+
+// Scale factor from a Cauchy distribution, redrawn while not positive and truncated to 1
+fn sample_scale_factor<R: Rng>(rng: &mut R, location: f32) -> f32 {
+    for _ in 0..MAX_REDRAWS {
+        let value = location + 0.1 * (std::f32::consts::PI * (rng.gen::<f32>() - 0.5)).tan();
+        if value > 0.0 {
+            return value.min(1.0);
+        }
+    }
+    // Only reached with a location far below zero
+    if location.is_nan() { f32::EPSILON } else { location.clamp(f32::EPSILON, 1.0) }
+}
+
+// Crossover probability from a normal distribution, truncated to [0, 1]
+fn sample_crossover_probability<R: Rng>(rng: &mut R, mean: f32) -> f32 {
+    (mean + 0.1 * rng.sample::<f32, _>(StandardNormal)).clamp(0.0, 1.0)
+}
+
+// Lehmer mean, weighted, or the previous value when the weights do not define one
+fn lehmer_mean(values: &[f32], weights: &[f32], previous: f32) -> f32 {
+    let numerator: f32 = values.iter().zip(weights).map(|(v, w)| w * v * v).sum();
+    let denominator: f32 = values.iter().zip(weights).map(|(v, w)| w * v).sum();
+    let mean = numerator / denominator;
+    if denominator > 0.0 && mean.is_finite() { mean } else { previous }
+}
+
+// Parameters of the trial taken from the mutant, as a step from the current individual
+fn cross<R: Rng>(rng: &mut R, crossover: Crossover, crossover_probability: f32, towards_mutant: [f32; 6]) -> [f32; 6] {
+    let mut step = [0.0; 6];
+    match crossover {
+        Crossover::Binomial => {
+            // At least one parameter comes from the mutant
+            let forced = rng.gen_range(0..6);
+            for j in 0..6 {
+                if j == forced || rng.gen::<f32>() < crossover_probability {
+                    step[j] = towards_mutant[j];
+                }
+            }
+        }
+        Crossover::Exponential => {
+            let start = rng.gen_range(0..6);
+            for length in 0..6 {
+                let j = (start + length) % 6;
+                step[j] = towards_mutant[j];
+                if rng.gen::<f32>() >= crossover_probability {
+                    break;
+                }
+            }
+        }
+    }
+    step
+}
+
+// Individual representation: Transform (translation + rotation)
+struct Individual {
+    transform: Transform,
+    fitness: f32,
+}
+
+// Scale factor and crossover probability of the trials that improved on their parent, with the improvement
+#[derive(Default)]
+struct Successes {
+    scale_factors: Vec<f32>,
+    crossover_probabilities: Vec<f32>,
+    improvements: Vec<f32>,
+}
+
+// Means the adaptive variants sample the scale factor and the crossover probability from
+struct AdaptationState {
+    mean_scale_factor: f32,
+    mean_crossover_probability: f32,
+    memory_scale_factor: Vec<f32>, // Historical memory of SHADE
+    memory_crossover_probability: Vec<f32>,
+    memory_index: usize,
+}
+
+impl AdaptationState {
+    fn new(adaptation: Adaptation) -> Self {
+        let memory_size = match adaptation { Adaptation::Shade { memory_size, .. } => memory_size, _ => 1 };
+        AdaptationState {
+            mean_scale_factor: 0.5,
+            mean_crossover_probability: 0.5,
+            memory_scale_factor: vec![0.5; memory_size],
+            memory_crossover_probability: vec![0.5; memory_size],
+            memory_index: 0,
+        }
+    }
+
+    // Scale factor and crossover probability of the next trial
+    fn sample<R: Rng>(&self, parameters: &DifferentialEvolution, rng: &mut R) -> (f32, f32) {
+        match parameters.adaptation {
+            Adaptation::None => (parameters.scale_factor, parameters.crossover_probability),
+            Adaptation::Jade { .. } => (
+                sample_scale_factor(rng, self.mean_scale_factor),
+                sample_crossover_probability(rng, self.mean_crossover_probability)
+            ),
+            Adaptation::Shade { .. } => {
+                let slot = rng.gen_range(0..self.memory_scale_factor.len());
+                (
+                    sample_scale_factor(rng, self.memory_scale_factor[slot]),
+                    sample_crossover_probability(rng, self.memory_crossover_probability[slot])
+                )
+            }
+        }
+    }
+
+    // Moves the means towards the parameters of the successful trials
+    fn update(&mut self, adaptation: Adaptation, successes: &Successes) {
+        if successes.scale_factors.is_empty() {
+            return;
+        }
+        match adaptation {
+            Adaptation::None => {}
+            Adaptation::Jade { learning_rate, .. } => {
+                let uniform = vec![1.0; successes.scale_factors.len()];
+                let mean_crossover = successes.crossover_probabilities.iter().sum::<f32>()
+                    / successes.crossover_probabilities.len() as f32;
+                self.mean_crossover_probability = (1.0 - learning_rate) * self.mean_crossover_probability
+                    + learning_rate * mean_crossover;
+                self.mean_scale_factor = (1.0 - learning_rate) * self.mean_scale_factor
+                    + learning_rate * lehmer_mean(&successes.scale_factors, &uniform, self.mean_scale_factor);
+            }
+            Adaptation::Shade { .. } => {
+                // Weighted by the fitness improvement
+                let total: f32 = successes.improvements.iter().sum();
+                if !total.is_finite() {
+                    return;
+                }
+                let count = successes.improvements.len();
+                let weights: Vec<f32> = if total > 0.0 {
+                    successes.improvements.iter().map(|d| d / total).collect()
+                } else {
+                    vec![1.0 / count as f32; count]
+                };
+                self.memory_crossover_probability[self.memory_index] = successes.crossover_probabilities
+                    .iter()
+                    .zip(weights.iter())
+                    .map(|(cr, w)| cr * w)
+                    .sum();
+                self.memory_scale_factor[self.memory_index] =
+                    lehmer_mean(&successes.scale_factors, &weights, self.memory_scale_factor[self.memory_index]);
+                self.memory_index = (self.memory_index + 1) % self.memory_scale_factor.len();
+            }
+        }
+    }
+}
+
+impl DifferentialEvolution {
+    // Mutation and crossover of the i-th individual
+    fn build_trial<R: Rng>(
+        &self,
+        rng: &mut R,
+        population: &[Individual],
+        ranking: &[usize],
+        archive: &[Transform],
+        i: usize,
+        (scale_factor, crossover_probability): (f32, f32)
+    ) -> Transform {
+        // Perform mutation: Create a mutant vector
+        let current = &population[i].transform;
+        let best = &population[ranking[0]].transform;
+        let mutant = match (self.adaptation, self.strategy) {
+            (Adaptation::Jade { p_best, .. } | Adaptation::Shade { p_best, .. }, _) => {
+                // current-to-pbest/1: x + F (pbest - x) + F (a - b), b from the population or the archive
+                let top = ((p_best * self.population_size as f32).ceil() as usize).clamp(1, self.population_size);
+                let p_best_individual = &population[ranking[rng.gen_range(0..top)]].transform;
+                let a = pick(rng, self.population_size, &[i], 1)[0];
+                let b = loop {
+                    let index = rng.gen_range(0..self.population_size + archive.len());
+                    if index != i && index != a { break index; }
+                };
+                let b = if b < self.population_size { &population[b].transform } else { &archive[b - self.population_size] };
+                ((difference(p_best_individual, current) + difference(&population[a].transform, b)) * scale_factor)
+                    .apply(current)
             }
-        })
-        .collect();
-
-    // Perform Differential Evolution
-    for g in 0..generations {
-        for i in 0..population_size {
-            // Select three random, distinct individuals (not including i)
-            let mut indices: Vec<usize> = (0..population_size).filter(|&idx| idx != i).collect();
-            indices.shuffle(&mut rng);
-            let (a, b, c) = (indices[0], indices[1], indices[2]);
-
-            // Perform mutation: Create a trial vector
-            let mutant_translation = population[a].transform.translation
-                + scale_factor
-                    * (population[b].transform.translation - population[c].transform.translation);
-
-            let mutant_rotation = Quat::from_euler(
-                bevy::math::EulerRot::XYZ,
-                population[a].transform.rotation.to_euler(bevy::math::EulerRot::XYZ).0
-                    + scale_factor
-                        * (population[b]
-                            .transform
-                            .rotation
-                            .to_euler(bevy::math::EulerRot::XYZ)
-                            .0
-                            - population[c]
-                                .transform
-                                .rotation
-                                .to_euler(bevy::math::EulerRot::XYZ)
-                                .0),
-                population[a].transform.rotation.to_euler(bevy::math::EulerRot::XYZ).1
-                    + scale_factor
-                        * (population[b]
-                            .transform
-                            .rotation
-                            .to_euler(bevy::math::EulerRot::XYZ)
-                            .1
-                            - population[c]
-                                .transform
-                                .rotation
-                                .to_euler(bevy::math::EulerRot::XYZ)
-                                .1),
-                population[a].transform.rotation.to_euler(bevy::math::EulerRot::XYZ).2
-                    + scale_factor
-                        * (population[b]
-                            .transform
-                            .rotation
-                            .to_euler(bevy::math::EulerRot::XYZ)
-                            .2
-                            - population[c]
-                                .transform
-                                .rotation
-                                .to_euler(bevy::math::EulerRot::XYZ)
-                                .2),
-            );
-
-            let mutant = Transform {
-                translation: mutant_translation,
-                rotation: mutant_rotation,
-                ..Default::default()
-            };
-
-            // Perform crossover
-            let mut trial = population[i].transform.clone();
-            if rng.gen::<f32>() < crossover_probability {
-                trial.translation = mutant.translation;
+            (Adaptation::None, Strategy::Rand1) => {
+                let r = pick(rng, self.population_size, &[i], 3);
+                (difference(&population[r[1]].transform, &population[r[2]].transform) * scale_factor)
+                    .apply(&population[r[0]].transform)
             }
-            if rng.gen::<f32>() < crossover_probability {
-                trial.rotation = mutant.rotation;
+            (Adaptation::None, Strategy::Best1) => {
+                let r = pick(rng, self.population_size, &[i, ranking[0]], 2);
+                (difference(&population[r[0]].transform, &population[r[1]].transform) * scale_factor)
+                    .apply(best)
+            }
+            (Adaptation::None, Strategy::CurrentToBest1) => {
+                let r = pick(rng, self.population_size, &[i, ranking[0]], 2);
+                ((difference(best, current) + difference(&population[r[0]].transform, &population[r[1]].transform))
+                    * scale_factor)
+                    .apply(current)
+            }
+            (Adaptation::None, Strategy::Rand2) => {
+                let r = pick(rng, self.population_size, &[i], 5);
+                ((difference(&population[r[1]].transform, &population[r[2]].transform)
+                    + difference(&population[r[3]].transform, &population[r[4]].transform))
+                    * scale_factor)
+                    .apply(&population[r[0]].transform)
+            }
+        };
+
+        // Perform crossover, parameter by parameter in the tangent space of the current individual
+        let towards_mutant = difference(&mutant, current).to_array();
+        PoseVector::from_array(cross(rng, self.crossover, crossover_probability, towards_mutant)).apply(current)
+    }
+
+    // Selection: Replace the parent if the trial is not worse
+    fn select(
+        &self,
+        parent: &mut Individual,
+        trial: Transform,
+        trial_fitness: f32,
+        (scale_factor, crossover_probability): (f32, f32),
+        successes: &mut Successes,
+        archive: &mut Vec<Transform>
+    ) {
+        if trial_fitness <= parent.fitness {
+            let improvement = parent.fitness - trial_fitness;
+            if improvement > 0.0 && self.adaptation != Adaptation::None {
+                // A parent without correspondences has an infinite fitness, its improvement would
+                // outweigh every other one
+                if improvement.is_finite() {
+                    successes.scale_factors.push(scale_factor);
+                    successes.crossover_probabilities.push(crossover_probability);
+                    successes.improvements.push(improvement);
+                }
+                archive.push(parent.transform);
+            }
+            parent.transform = trial;
+            parent.fitness = trial_fitness;
+        }
+    }
+}
+
+impl Registrar for DifferentialEvolution {
+    fn name(&self) -> &str {
+        match self.mode {
+            Mode::Asynchronous => "DE",
+            Mode::Synchronous => "DE-Sync"
+        }
+    }
+
+    fn register(
+        &self,
+        source: &[[f32; 3]],
+        target: &[[f32; 3]],
+        initial: &InitialGuess
+    ) -> Result<RegistrationResult, RegistrationError> {
+        if source.is_empty() || target.is_empty() {
+            return Err(RegistrationError::EmptyPointCloud);
+        }
+
+        // The trials are built sequentially from the main stream, the initial population from one stream per individual
+        let streams = RandomStreams::new(self.seed);
+        let mut rng = streams.main();
+
+        let context = FitnessContext::with_options(source, target, &self.correspondences);
+
+        // Initialize the population
+        let mut population: Vec<Individual> = (0..self.population_size)
+            .into_par_iter()
+            .map(|i| {
+                let mut rng = streams.stream(0, i);
+                // The initial guess itself is the first individual
+                let transform = if i == 0 { initial.pose } else { initial.sample(&mut rng) };
+
+                let fitness_value = context.evaluate(&transform);
+
+                Individual {
+                    transform,
+                    fitness: fitness_value,
+                }
+            })
+            .collect();
+
+        let mut adaptation = AdaptationState::new(self.adaptation);
+        let mut archive: Vec<Transform> = Vec::new(); // Parents replaced by a better trial
+
+        let mut history = Vec::new();
+        let mut termination = Termination::MaxIterations;
+
+        // Perform Differential Evolution
+        for g in 0..self.generations {
+            // Ranking at the start of the generation, for the best and p-best individuals
+            let mut ranking: Vec<usize> = (0..self.population_size).collect();
+            ranking.sort_by(|&a, &b| population[a].fitness.total_cmp(&population[b].fitness));
+
+            let mut successes = Successes::default();
+
+            match self.mode {
+                Mode::Asynchronous => {
+                    for i in 0..self.population_size {
+                        let parameters = adaptation.sample(self, &mut rng);
+                        let trial = self.build_trial(&mut rng, &population, &ranking, &archive, i, parameters);
+
+                        // Evaluate trial individual
+                        let trial_fitness = context.evaluate(&trial);
+
+                        self.select(&mut population[i], trial, trial_fitness, parameters, &mut successes, &mut archive);
+                    }
+                }
+                Mode::Synchronous => {
+                    // Every trial is built from the population of the previous generation
+                    let trials: Vec<(Transform, (f32, f32))> = (0..self.population_size)
+                        .map(|i| {
+                            let parameters = adaptation.sample(self, &mut rng);
+                            (self.build_trial(&mut rng, &population, &ranking, &archive, i, parameters), parameters)
+                        })
+                        .collect();
+
+                    // Evaluate trial individuals in parallel
+                    let trial_fitness: Vec<f32> = trials
+                        .par_iter()
+                        .map(|(trial, _)| context.evaluate(trial))
+                        .collect();
+
+                    for (i, ((trial, parameters), fitness)) in trials.into_iter().zip(trial_fitness).enumerate() {
+                        self.select(&mut population[i], trial, fitness, parameters, &mut successes, &mut archive);
+                    }
+                }
+            }
+
+            // Adapt the parameters to the successful ones
+            adaptation.update(self.adaptation, &successes);
+
+            // The archive is kept at the population size by dropping random parents
+            while archive.len() > self.population_size {
+                let index = rng.gen_range(0..archive.len());
+                archive.swap_remove(index);
             }
 
-            // Evaluate trial individual
-            let trial_fitness = fitness(&trial, &source_points, &target_points);
+            // Check for convergence
+            let best_fitness = population.iter().map(|ind| ind.fitness).fold(f32::INFINITY, f32::min);
+            history.push(best_fitness);
+            if self.verbose { println!("Generation {} | Best fitness: {}", g, best_fitness); }
 
-            // Selection: Replace if the trial is better
-            if trial_fitness < population[i].fitness {
-                population[i].transform = trial;
-                population[i].fitness = trial_fitness;
+            if best_fitness < self.convergence_threshold {
+                termination = Termination::Converged;
+                break;
             }
         }
 
-        // Check for convergence
-        let best_fitness = population.iter().map(|ind| ind.fitness).fold(f32::INFINITY, f32::min);
-        if verbose { println!("Generation {} | Best fitness: {}", g, best_fitness); }
+        // Return the best solution
+        if let Some(best_individual) = population.iter().min_by(|a, b| a.fitness.total_cmp(&b.fitness)) {
+            Ok(RegistrationResult {
+                transform: best_individual.transform,
+                fitness: best_individual.fitness,
+                iterations: history.len(),
+                evaluations: context.evaluations(),
+                termination,
+                overlap: context.overlap(&best_individual.transform),
+                seed: Some(streams.seed()),
+                history
+            })
+        } else {
+            Err(RegistrationError::NotConverged { solver: self.name().to_string() })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_chacha::ChaCha8Rng;
+    use crate::solvers::InitialGuess;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-6, "{} != {}", actual, expected);
+    }
+
+    fn successes(scale_factors: &[f32], improvements: &[f32]) -> Successes {
+        Successes {
+            scale_factors: scale_factors.to_vec(),
+            crossover_probabilities: vec![0.8; scale_factors.len()],
+            improvements: improvements.to_vec(),
+        }
+    }
+
+    #[test]
+    fn lehmer_mean_is_weighted_towards_large_values() {
+        assert_close(lehmer_mean(&[0.2, 0.4], &[0.5, 0.5], 0.5), 0.1 / 0.3);
+        assert_close(lehmer_mean(&[0.2, 0.4], &[1.0, 0.0], 0.5), 0.2);
+        // Undefined means keep the previous value
+        assert_close(lehmer_mean(&[], &[], 0.5), 0.5);
+        assert_close(lehmer_mean(&[0.2, 0.4], &[0.0, 0.0], 0.5), 0.5);
+        assert_close(lehmer_mean(&[0.2, 0.4], &[f32::NAN, 0.5], 0.5), 0.5);
+        assert_close(lehmer_mean(&[0.2, 0.4], &[f32::INFINITY, 0.5], 0.5), 0.5);
+    }
 
-        if best_fitness < convergence_threshold {
-            break;
+    #[test]
+    fn scale_factors_are_in_the_unit_interval() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for location in [0.0, 0.5, 1.0, 3.0] {
+            for _ in 0..1000 {
+                let value = sample_scale_factor(&mut rng, location);
+                assert!(value > 0.0 && value <= 1.0, "{}", value);
+            }
         }
+        // Locations that can not give a positive value fall back instead of redrawing forever
+        assert_eq!(sample_scale_factor(&mut rng, -1e6), f32::EPSILON);
+        assert_eq!(sample_scale_factor(&mut rng, f32::NAN), f32::EPSILON);
+    }
+
+    #[test]
+    fn exponential_crossover_takes_a_run_of_parameters() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let towards_mutant = [1.0; 6];
+        let taken = |step: [f32; 6]| step.iter().filter(|&&value| value != 0.0).count();
+        for _ in 0..100 {
+            assert_eq!(taken(cross(&mut rng, Crossover::Exponential, 0.0, towards_mutant)), 1);
+            assert_eq!(taken(cross(&mut rng, Crossover::Exponential, 1.0, towards_mutant)), 6);
+            assert_eq!(taken(cross(&mut rng, Crossover::Binomial, 0.0, towards_mutant)), 1);
+
+            // Consecutive parameters, wrapping around: a single start of a run
+            let step = cross(&mut rng, Crossover::Exponential, 0.5, towards_mutant);
+            let starts = (0..6).filter(|&j| step[j] != 0.0 && step[(j + 5) % 6] == 0.0).count();
+            assert!(starts == 1 || taken(step) == 6, "{:?}", step);
+        }
+    }
+
+    #[test]
+    fn mean_length_of_exponential_crossover() {
+        // Geometric length truncated to 6: (1 - CR^6) / (1 - CR)
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let trials = 20000;
+        let total: usize = (0..trials)
+            .map(|_| cross(&mut rng, Crossover::Exponential, 0.5, [1.0; 6]).iter().filter(|&&v| v != 0.0).count())
+            .sum();
+        let mean = total as f32 / trials as f32;
+        assert!((mean - (1.0 - 0.5f32.powi(6)) / 0.5).abs() < 0.05, "{}", mean);
+    }
+
+    #[test]
+    fn update_without_successes_keeps_the_means() {
+        for adaptation in [
+            Adaptation::Jade { learning_rate: 0.1, p_best: 0.05 },
+            Adaptation::Shade { memory_size: 3, p_best: 0.1 },
+        ] {
+            let mut state = AdaptationState::new(adaptation);
+            state.update(adaptation, &Successes::default());
+            assert_eq!(state.mean_scale_factor, 0.5);
+            assert_eq!(state.memory_scale_factor, vec![0.5; state.memory_scale_factor.len()]);
+            assert_eq!(state.memory_index, 0);
+        }
+    }
+
+    #[test]
+    fn shade_update_weights_by_improvement() {
+        let adaptation = Adaptation::Shade { memory_size: 2, p_best: 0.1 };
+        let mut state = AdaptationState::new(adaptation);
+        state.update(adaptation, &successes(&[0.2, 0.4], &[1.0, 1.0]));
+        assert_close(state.memory_scale_factor[0], 0.1 / 0.3);
+        assert_close(state.memory_crossover_probability[0], 0.8);
+        assert_eq!(state.memory_index, 1);
+
+        // Zero improvements are weighted uniformly
+        state.update(adaptation, &successes(&[0.2, 0.4], &[0.0, 0.0]));
+        assert_close(state.memory_scale_factor[1], 0.1 / 0.3);
+        assert_eq!(state.memory_index, 0);
+    }
+
+    #[test]
+    fn shade_update_ignores_infinite_improvements() {
+        let adaptation = Adaptation::Shade { memory_size: 2, p_best: 0.1 };
+        let mut state = AdaptationState::new(adaptation);
+        state.update(adaptation, &successes(&[0.9, 0.2], &[f32::INFINITY, 1.0]));
+        assert_eq!(state.memory_scale_factor, vec![0.5, 0.5]);
+        assert_eq!(state.memory_index, 0);
+    }
+
+    #[test]
+    fn improvement_over_an_infinite_parent_is_not_a_success() {
+        let de = DifferentialEvolution {
+            adaptation: Adaptation::Shade { memory_size: 5, p_best: 0.1 },
+            ..Default::default()
+        };
+        let mut successes = Successes::default();
+        let mut archive = Vec::new();
+
+        // No correspondence of the parent was accepted
+        let mut parent = Individual { transform: Transform::IDENTITY, fitness: f32::INFINITY };
+        let trial = Transform::from_xyz(1.0, 0.0, 0.0);
+        de.select(&mut parent, trial, 0.5, (0.7, 0.9), &mut successes, &mut archive);
+        assert_eq!(parent.transform, trial);
+        assert_eq!(parent.fitness, 0.5);
+        assert!(successes.improvements.is_empty() && successes.scale_factors.is_empty());
+        assert_eq!(archive.len(), 1);
+
+        let mut better = Individual { transform: Transform::IDENTITY, fitness: 2.0 };
+        de.select(&mut better, trial, 0.5, (0.7, 0.9), &mut successes, &mut archive);
+        assert_eq!(successes.improvements, vec![1.5]);
     }
 
-    // Return the best solution
-    if let Some(best_individual) = population.iter().min_by(|a, b| a.fitness.partial_cmp(&b.fitness).unwrap()) {
-        Ok(best_individual.transform.clone())
-    } else {
-        Err("Failed to find a solution.".to_string())
+    // Registration with many trials without any correspondence, which used to stop the adaptation
+    // with a NaN scale factor and hang
+    #[test]
+    fn shade_with_rejected_correspondences_terminates() {
+        let cloud: Vec<[f32; 3]> = (0..64)
+            .map(|i| [(i % 4) as f32 * 0.1, (i / 4 % 4) as f32 * 0.1, (i / 16) as f32 * 0.1])
+            .collect();
+        let de = DifferentialEvolution {
+            population_size: 30,
+            generations: 20,
+            adaptation: Adaptation::Shade { memory_size: 5, p_best: 0.1 },
+            convergence_threshold: 0.0,
+            correspondences: CorrespondenceOptions { max_distance: Some(0.3), ..Default::default() },
+            seed: Some(1),
+            ..Default::default()
+        };
+        let initial = InitialGuess { pose: Transform::IDENTITY, translation_radius: 5.0, rotation_radius: 3.0 };
+        let result = de.register(&cloud, &cloud, &initial).unwrap();
+        assert_eq!(result.iterations, 20);
+        assert!(result.fitness.is_finite());
     }
 }
@@ -1,145 +1,291 @@
 use rand::prelude::*;
-use bevy::math::{Quat, Vec3};
-use bevy::prelude::Transform;
+use rand_distr::StandardNormal;
+use nalgebra::Vector6;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::utils::fitness;
-
-pub fn evolution_strategy(
-    source: &Vec<[f32; 3]>,
-    target: &Vec<[f32; 3]>,
-    population_size: usize,
-    generations: usize,
-    _learning_rate: f32,
-    convergence_threshold: f32,
-    verbose: bool
-) -> Result<Transform, String> {
-    if source.is_empty() || target.is_empty() {
-        return Err("Source or target point cloud is empty.".to_string());
+use crate::utils::FitnessContext;
+use crate::error::RegistrationError;
+use crate::robust::CorrespondenceOptions;
+use super::pose::PoseVector;
+use super::random::RandomStreams;
+use super::{InitialGuess, Registrar, RegistrationResult, Termination};
+
+/// Individuals the next parents are selected from
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Selection {
+    Comma, // (μ,λ): only the offspring
+    Plus,  // (μ+λ): the parents and the offspring
+}
+
+/// How the mutation step size evolves
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum StepSizeAdaptation {
+    OneFifth,  // Single step size, increased while more than 1/5 of the offspring improve on their parent
+    LogNormal, // Every individual carries its own step size, mutated log-normally before its position
+}
+
+/// How the parents are combined into the parent of the offspring
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Recombination {
+    Intermediate, // Mean of the parents
+    Weighted,     // Weighted mean, the better the parent the larger its weight
+}
+
+/// Evolution strategy parameters
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EvolutionStrategy {
+    pub parents: usize,   // μ
+    pub offspring: usize, // λ
+    pub generations: usize,
+    pub selection: Selection,
+    pub step_size_adaptation: StepSizeAdaptation,
+    pub recombination: Recombination,
+    pub initial_step_size: f32, // Relative to the search radii of the initial guess
+    pub min_step_size: f32,     // The search has stagnated below this step size
+    pub convergence_threshold: f32,
+    pub correspondences: CorrespondenceOptions,
+    pub seed: Option<u64>,
+    pub verbose: bool,
+}
+
+impl Default for EvolutionStrategy {
+    fn default() -> Self {
+        Self {
+            parents: 15,
+            offspring: 100,
+            generations: 100,
+            selection: Selection::Comma,
+            step_size_adaptation: StepSizeAdaptation::LogNormal,
+            recombination: Recombination::Weighted,
+            initial_step_size: 0.3,
+            min_step_size: 1e-5,
+            convergence_threshold: 0.5,
+            correspondences: CorrespondenceOptions::default(),
+            seed: None,
+            verbose: false,
+        }
     }
+}
 
-    // Preprocess points into Vec3
-    let source_points: Vec<Vec3> = source.iter().map(|&p| Vec3::from(p)).collect();
-    let target_points: Vec<Vec3> = target.iter().map(|&p| Vec3::from(p)).collect();
+impl EvolutionStrategy {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.parents == 0 || self.offspring == 0 {
+            return Err("parents and offspring must be at least 1".to_string());
+        }
+        if self.selection == Selection::Comma && self.offspring < self.parents {
+            return Err("comma selection needs at least as many offspring as parents".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Factor applied to the step size by the one-fifth success rule (Rechenberg)
+const ONE_FIFTH_FACTOR: f32 = 0.817;
 
-    // Individual representation: Transform (translation + rotation)
-    struct Individual {
-        transform: Transform,
-        fitness: f32,
+impl Registrar for EvolutionStrategy {
+    fn name(&self) -> &str {
+        "ES"
     }
 
-    // Initialize the population with random transforms
-    let mut population: Vec<Individual> = (0..population_size)
-        .into_par_iter()
-        .map(|_| {
-            let mut rng = rand::thread_rng(); // Thread-local RNG for safe random number generation
-            let transform = Transform {
-                translation: Vec3::new(
-                    rng.gen_range(-1.0..1.0),
-                    rng.gen_range(-1.0..1.0),
-                    rng.gen_range(-1.0..1.0),
-                ),
-                rotation: Quat::from_euler(
-                    bevy::math::EulerRot::XYZ,
-                    rng.gen_range(-std::f32::consts::PI..std::f32::consts::PI),
-                    rng.gen_range(-std::f32::consts::PI..std::f32::consts::PI),
-                    rng.gen_range(-std::f32::consts::PI..std::f32::consts::PI),
-                ),
-                ..Default::default()
-            };
+    fn register(
+        &self,
+        source: &[[f32; 3]],
+        target: &[[f32; 3]],
+        initial: &InitialGuess
+    ) -> Result<RegistrationResult, RegistrationError> {
+        if source.is_empty() || target.is_empty() {
+            return Err(RegistrationError::EmptyPointCloud);
+        }
 
-            let fitness_value = fitness(&transform, &source_points, &target_points);
+        let context = FitnessContext::with_options(source, target, &self.correspondences);
 
-            Individual {
-                transform,
-                fitness: fitness_value,
-            }
-        })
-        .collect();
-
-    // Evolution loop
-    for g in 0..generations {
-        // Compute the mean and standard deviation of the population
-        let mean_translation: Vec3 = population
-            .iter()
-            .map(|ind| ind.transform.translation)
-            .fold(Vec3::ZERO, |acc, t| acc + t)
-            / population_size as f32;
-
-        let mean_rotation: Quat = population
-            .iter()
-            .map(|ind| ind.transform.rotation)
-            .fold(Quat::IDENTITY, |acc, r| acc * r)
-            / population_size as f32;
-
-        let std_dev_translation: Vec3 = population
-            .iter()
-            .map(|ind| {
-                let diff = ind.transform.translation - mean_translation;
-                Vec3::new(diff.x * diff.x, diff.y * diff.y, diff.z * diff.z)
-            })
-            .fold(Vec3::ZERO, |acc, d| acc + d)
-            / population_size as f32;
-
-        let std_dev_translation = Vec3::new(
-            std_dev_translation.x.sqrt(),
-            std_dev_translation.y.sqrt(),
-            std_dev_translation.z.sqrt(),
-        );
-
-        // Mutation: Add Gaussian noise to each individual
-        let mutated_population: Vec<Individual> = (0..population_size)
-            .into_par_iter() // Convert the range into a parallel iterator
-            .map(|_| {
-                let mut rng = rand::thread_rng(); // Thread-local RNG for safe random number generation
-                let translation = Vec3::new(
-                    mean_translation.x + rng.gen::<f32>() * std_dev_translation.x,
-                    mean_translation.y + rng.gen::<f32>() * std_dev_translation.y,
-                    mean_translation.z + rng.gen::<f32>() * std_dev_translation.z,
-                );
-
-                let rotation = Quat::from_euler(
-                    bevy::math::EulerRot::XYZ,
-                    mean_rotation.to_euler(bevy::math::EulerRot::XYZ).0
-                        + rng.gen_range(-0.1..0.1),
-                    mean_rotation.to_euler(bevy::math::EulerRot::XYZ).1
-                        + rng.gen_range(-0.1..0.1),
-                    mean_rotation.to_euler(bevy::math::EulerRot::XYZ).2
-                        + rng.gen_range(-0.1..0.1),
-                );
-
-                let transform = Transform {
-                    translation,
-                    rotation,
-                    ..Default::default()
-                };
-
-                let fitness_value = fitness(&transform, &source_points, &target_points);
+        // Individual representation: offset from the initial pose, relative to the search radii
+        #[derive(Clone)]
+        struct Individual {
+            offset: Vector6<f32>,
+            step_size: f32,
+            fitness: f32,
+        }
+
+        // Recombination weights of the parents, sorted from best to worst
+        let weights: Vec<f32> = match self.recombination {
+            Recombination::Intermediate => vec![1.0; self.parents],
+            Recombination::Weighted => (0..self.parents)
+                .map(|i| (self.parents as f32 + 0.5).ln() - (i as f32 + 1.0).ln())
+                .collect(),
+        };
+        let weights_sum: f32 = weights.iter().sum();
+        let weights: Vec<f32> = weights.iter().map(|w| w / weights_sum).collect();
+
+        // Learning rate of the log-normal self-adaptation, 1/sqrt(2n) for the n = 6 pose coordinates
+        let tau = 1.0 / (2.0 * 6.0f32).sqrt();
+
+        // One random stream per individual, independent of the thread that evaluates it
+        let streams = RandomStreams::new(self.seed);
+
+        // Initialize the parents with random offsets inside the search region
+        let mut parents: Vec<Individual> = (0..self.parents)
+            .into_par_iter()
+            .map(|i| {
+                let mut rng = streams.stream(0, i);
+                // The initial guess itself is the first individual
+                let offset = if i == 0 { PoseVector::ZERO } else { initial.sample_offset(&mut rng) };
 
                 Individual {
-                    transform,
-                    fitness: fitness_value,
+                    offset: offset.into(),
+                    step_size: self.initial_step_size,
+                    fitness: context.evaluate(&initial.transform_at(&offset)),
                 }
             })
             .collect();
+        parents.sort_by(|a, b| a.fitness.total_cmp(&b.fitness));
 
-        // Selection: Keep the best individuals
-        population.extend(mutated_population);
-        population.sort_by(|a, b| a.fitness.partial_cmp(&b.fitness).unwrap());
-        population.truncate(population_size);
+        let mut best = parents[0].clone();
+        let mut step_size = self.initial_step_size; // Shared by every individual with the one-fifth rule
+        let mut history = Vec::new();
+        let mut termination = Termination::MaxIterations;
 
-        if verbose { println!("Generation {} | Best fitness: {}", g, population[0].fitness); }
+        // Evolution loop
+        for g in 0..self.generations {
+            // Recombination: a single parent for every offspring
+            let offset: Vector6<f32> = parents.iter().zip(weights.iter()).map(|(p, w)| p.offset * *w).sum();
+            let parent_step_size = match self.step_size_adaptation {
+                StepSizeAdaptation::OneFifth => step_size,
+                StepSizeAdaptation::LogNormal => parents.iter().zip(weights.iter()).map(|(p, w)| p.step_size * w).sum()
+            };
 
-        // Check convergence
-        if population[0].fitness < convergence_threshold {
-            break;
+            // Mutation: Add Gaussian noise, scaled by the step size
+            let offspring: Vec<Individual> = (0..self.offspring)
+                .into_par_iter()
+                .map(|i| {
+                    let mut rng = streams.stream(g + 1, i);
+                    let step_size = match self.step_size_adaptation {
+                        StepSizeAdaptation::OneFifth => parent_step_size,
+                        StepSizeAdaptation::LogNormal =>
+                            parent_step_size * (tau * rng.sample::<f32, _>(StandardNormal)).exp()
+                    };
+                    let noise = Vector6::from_fn(|_, _| rng.sample::<f32, _>(StandardNormal));
+                    let offset = offset + noise * step_size;
+
+                    Individual {
+                        offset,
+                        step_size,
+                        fitness: context.evaluate(&initial.transform_at(&offset.into())),
+                    }
+                })
+                .collect();
+
+            // One-fifth success rule, measured against the recombined parent
+            if self.step_size_adaptation == StepSizeAdaptation::OneFifth {
+                let parent_fitness = context.evaluate(&initial.transform_at(&offset.into()));
+                let successes = offspring.iter().filter(|o| o.fitness < parent_fitness).count();
+                let success_rate = successes as f32 / self.offspring as f32;
+                if success_rate > 0.2 {
+                    step_size /= ONE_FIFTH_FACTOR;
+                } else if success_rate < 0.2 {
+                    step_size *= ONE_FIFTH_FACTOR;
+                }
+            }
+
+            // Selection: Keep the best individuals
+            let mut pool = offspring;
+            if self.selection == Selection::Plus {
+                pool.extend(parents);
+            }
+            pool.sort_by(|a, b| a.fitness.total_cmp(&b.fitness));
+            pool.truncate(self.parents);
+            parents = pool;
+
+            // The comma selection may forget the best individual found so far
+            if parents[0].fitness < best.fitness {
+                best = parents[0].clone();
+            }
+            history.push(best.fitness);
+
+            if self.verbose { println!("Generation {} | Best fitness: {} | Step size: {}", g, best.fitness, parent_step_size); }
+
+            // Check convergence
+            if best.fitness < self.convergence_threshold {
+                termination = Termination::Converged;
+                break;
+            }
+            if parent_step_size < self.min_step_size {
+                termination = Termination::Stagnated;
+                break;
+            }
+        }
+
+        // Return the best solution
+        if best.fitness.is_finite() {
+            let transform = initial.transform_at(&best.offset.into());
+            Ok(RegistrationResult {
+                transform,
+                fitness: best.fitness,
+                iterations: history.len(),
+                evaluations: context.evaluations(),
+                termination,
+                overlap: context.overlap(&transform),
+                seed: Some(streams.seed()),
+                history
+            })
+        } else {
+            Err(RegistrationError::NotConverged { solver: self.name().to_string() })
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::solvers::fixtures::{assert_recovers_motion, clouds, initial_guess};
+
+    fn es() -> EvolutionStrategy {
+        EvolutionStrategy {
+            parents: 5,
+            offspring: 30,
+            generations: 40,
+            convergence_threshold: 1e-6,
+            seed: Some(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn every_variant_recovers_a_small_motion() {
+        let (source, target) = clouds();
+        // Every option in two of the runs
+        let variants = [
+            (Selection::Comma, StepSizeAdaptation::LogNormal, Recombination::Weighted),
+            (Selection::Comma, StepSizeAdaptation::OneFifth, Recombination::Intermediate),
+            (Selection::Plus, StepSizeAdaptation::OneFifth, Recombination::Weighted),
+            (Selection::Plus, StepSizeAdaptation::LogNormal, Recombination::Intermediate),
+        ];
+        for (selection, step_size_adaptation, recombination) in variants {
+            let es = EvolutionStrategy { selection, step_size_adaptation, recombination, ..es() };
+            let result = es.register(&source, &target, &initial_guess()).unwrap();
+            assert_recovers_motion(&result, 1e-2, 2e-2);
+        }
+    }
+
+    #[test]
+    fn is_reproducible_from_the_seed() {
+        let (source, target) = clouds();
+        let es = EvolutionStrategy { generations: 10, ..es() };
+        let first = es.register(&source, &target, &initial_guess()).unwrap();
+        let second = es.register(&source, &target, &initial_guess()).unwrap();
+        assert_eq!(first.transform, second.transform);
+        assert_eq!(first.history, second.history);
+        assert_eq!(first.seed, Some(1));
+    }
 
-    // Return the best solution
-    if let Some(best_individual) = population.first() {
-        Ok(best_individual.transform.clone())
-    } else {
-        Err("Failed to find a solution.".to_string())
+    #[test]
+    fn validates_the_population() {
+        assert!(EvolutionStrategy::default().validate().is_ok());
+        assert!(EvolutionStrategy { parents: 0, ..Default::default() }.validate().is_err());
+        assert!(EvolutionStrategy { offspring: 10, parents: 15, ..Default::default() }.validate().is_err());
+        let plus = EvolutionStrategy { offspring: 10, parents: 15, selection: Selection::Plus, ..Default::default() };
+        assert!(plus.validate().is_ok());
     }
 }
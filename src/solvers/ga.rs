@@ -1,157 +1,389 @@
 use rand::prelude::*;
-use bevy::math::{Quat, Vec3};
+use rand_distr::StandardNormal;
+use bevy::math::Vec3;
 use bevy::prelude::Transform;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::utils::fitness;
-
-pub fn genetic_algorithm(
-    source: &Vec<[f32; 3]>,
-    target: &Vec<[f32; 3]>,
-    population_size: usize,
-    generations: usize,
-    mutation_rate: f32,
-    tournament_size: usize,
-    convergence_threshold: f32,
-    stopping_threshold: usize, // If there is no improvement in stopping_threshold iterations, stop
-    verbose: bool
-) -> Result<Transform, String> {
-    if source.is_empty() || target.is_empty() {
-        return Err("Source or target point cloud is empty.".to_string());
-    }
-
-    // Initialize population
-    let mut rng = thread_rng();
-    let mut population: Vec<Transform> = (0..population_size)
-        .into_par_iter()
-        .map(|_| {
-            let mut rng = rand::thread_rng();
-            Transform {
-                translation: Vec3::new(
-                    rng.gen_range(-1.0..1.0),
-                    rng.gen_range(-1.0..1.0),
-                    rng.gen_range(-1.0..1.0),
-                ),
-                rotation: Quat::from_euler(
-                    bevy::math::EulerRot::XYZ,
-                    rng.gen_range(-std::f32::consts::PI..std::f32::consts::PI),
-                    rng.gen_range(-std::f32::consts::PI..std::f32::consts::PI),
-                    rng.gen_range(-std::f32::consts::PI..std::f32::consts::PI),
-                ),
-                ..Default::default()
-            }
-        })
-        .collect();
-
-    // Pre-process points into Bevy Vec3 for easier calculations
-    let source_points: Vec<Vec3> = source.iter().map(|&p| Vec3::from(p)).collect();
-    let target_points: Vec<Vec3> = target.iter().map(|&p| Vec3::from(p)).collect();
-
-    let mut best_transform = None;
-    let mut best_fitness = f32::INFINITY;
-    let mut no_improvement_counter = 0;
-
-    for g in 0..generations {
-        // Evaluate fitness
-        let mut fitness_scores: Vec<(f32, &Transform)> = population
-            .iter()
-            .map(|t| (fitness(t, &source_points, &target_points), t))
-            .collect();
+use crate::utils::FitnessContext;
+use crate::error::RegistrationError;
+use crate::robust::CorrespondenceOptions;
+use super::pose::PoseVector;
+use super::random::RandomStreams;
+use super::{InitialGuess, Registrar, RegistrationResult, Termination};
 
-        fitness_scores.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+/// How the parents are chosen
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Selection {
+    Tournament, // Best of tournament_size random individuals
+    Truncation, // Any individual of the best half
+    Roulette,   // Probability proportional to the inverse of the fitness
+    Rank,       // Probability proportional to the rank, the best has the largest
+}
+
+/// How two parents are combined, gene by gene. The genes are the translation and the rotation vector
+/// of the second parent relative to the first one.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Crossover {
+    #[serde(rename = "BLX")]
+    Blx { alpha: f32 }, // Uniform in the range of the parents, extended by alpha times its length
+    #[serde(rename = "SBX")]
+    Sbx { eta: f32 },   // Simulated binary, the larger eta the closer to the parents
+    Uniform,            // Every gene from either parent
+}
+
+/// Genetic algorithm parameters
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GeneticAlgorithm {
+    pub population_size: usize,
+    pub generations: usize,
+    pub elitism: usize,            // Best individuals copied unchanged into the next generation
+    pub selection: Selection,
+    pub tournament_size: usize,
+    pub crossover: Crossover,
+    pub mutation_rate: f32,        // Probability of mutating an individual
+    pub mutation_step_size: f32,   // Standard deviation of the mutation, relative to the search radii
+    pub mutation_decay: f32,       // Factor applied to the mutation step size after every generation
+    pub convergence_threshold: f32,
+    pub stopping_threshold: usize, // If there is no improvement in stopping_threshold iterations, stop
+    pub correspondences: CorrespondenceOptions,
+    pub seed: Option<u64>,
+    pub verbose: bool,
+}
+
+impl Default for GeneticAlgorithm {
+    fn default() -> Self {
+        Self {
+            population_size: 100,
+            generations: 100,
+            elitism: 1,
+            selection: Selection::Tournament,
+            tournament_size: 3,
+            crossover: Crossover::Blx { alpha: 0.5 },
+            mutation_rate: 0.3,
+            mutation_step_size: 0.1,
+            mutation_decay: 0.97,
+            convergence_threshold: 0.5,
+            stopping_threshold: 100,
+            correspondences: CorrespondenceOptions::default(),
+            seed: None,
+            verbose: false,
+        }
+    }
+}
 
-        // Update the best transform
-        if fitness_scores[0].0 < best_fitness {
-            best_fitness = fitness_scores[0].0;
-            best_transform = Some(*fitness_scores[0].1);
-            no_improvement_counter = 0; // Reset counter if improvement
-            if verbose { println!("Generation {} | Best fitness: {}", g, best_fitness); }
-        } else {
-            no_improvement_counter += 1;
+impl GeneticAlgorithm {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.population_size < 2 {
+            return Err("the population needs at least 2 individuals".to_string());
+        }
+        if self.elitism >= self.population_size {
+            return Err("elitism must be smaller than the population size".to_string());
         }
+        if self.selection == Selection::Tournament && self.tournament_size == 0 {
+            return Err("tournament_size must be at least 1".to_string());
+        }
+        Ok(())
+    }
 
-        // Stop if no improvement in the last stopping_threshold generations
-        if no_improvement_counter >= stopping_threshold {
-            if verbose { 
-                println!("No improvement in the last {} generations. Stopping early.", stopping_threshold) 
+    // Index of a parent in the population, sorted from best to worst
+    fn select<R: Rng>(&self, rng: &mut R, ranked: &[(Transform, f32)]) -> usize {
+        let n = ranked.len();
+        match self.selection {
+            // The population is sorted, so the best of the tournament has the smallest index
+            Selection::Tournament => (0..self.tournament_size).map(|_| rng.gen_range(0..n)).min().unwrap(),
+            Selection::Truncation => rng.gen_range(0..(n / 2).max(1)),
+            Selection::Roulette => {
+                let weights: Vec<f32> = ranked
+                    .iter()
+                    .map(|(_, fitness)| if fitness.is_finite() { 1.0 / (fitness + 1e-6) } else { 0.0 })
+                    .collect();
+                spin(rng, &weights)
+            }
+            Selection::Rank => {
+                let weights: Vec<f32> = (0..n).map(|rank| (n - rank) as f32).collect();
+                spin(rng, &weights)
+            }
+        }
+    }
+
+    // Child of two parents, gene by gene in the tangent space of the first parent
+    fn cross<R: Rng>(&self, rng: &mut R, parent1: &Transform, parent2: &Transform) -> Transform {
+        let genes = PoseVector::between(parent1, parent2).to_array();
+        let mut child = [0.0; 6];
+        for (gene, &other) in child.iter_mut().zip(genes.iter()) {
+            // The first parent is at 0, the second one at `other`
+            *gene = match self.crossover {
+                Crossover::Blx { alpha } => {
+                    let (low, high) = (other.min(0.0), other.max(0.0));
+                    let extension = alpha * (high - low);
+                    rng.gen_range(low - extension..=high + extension)
+                }
+                Crossover::Sbx { eta } => {
+                    let u: f32 = rng.gen();
+                    let beta = if u <= 0.5 {
+                        (2.0 * u).powf(1.0 / (eta + 1.0))
+                    } else {
+                        (1.0 / (2.0 * (1.0 - u))).powf(1.0 / (eta + 1.0))
+                    };
+                    // Either of the two children, at the same distance from their parent
+                    if rng.gen::<bool>() { 0.5 * (1.0 - beta) * other } else { 0.5 * (1.0 + beta) * other }
+                }
+                Crossover::Uniform => if rng.gen::<bool>() { other } else { 0.0 }
             };
-            break;
         }
+        PoseVector::from_array(child).apply(parent1)
+    }
+}
 
-        // Check for convergence
-        if best_fitness < convergence_threshold {
-            break;
+// Roulette wheel: index drawn with a probability proportional to its weight
+fn spin<R: Rng>(rng: &mut R, weights: &[f32]) -> usize {
+    let total: f32 = weights.iter().sum();
+    if total <= 0.0 {
+        return rng.gen_range(0..weights.len());
+    }
+    let mut position = rng.gen::<f32>() * total;
+    for (index, weight) in weights.iter().enumerate() {
+        position -= weight;
+        if position <= 0.0 {
+            return index;
         }
+    }
+    weights.len() - 1
+}
 
-        // Truncated selection
-        /*let selected: Vec<Transform> = fitness_scores
-            .iter()
-            .take(population_size / 2)
-            .map(|(_, t)| **t)
-            .collect();*/
-
-        // Tournament selection
-        let selected: Vec<Transform> = (0..population_size / 2)
-            .map(|_| {
-                // Build a tournament
-                let tournament: Vec<&(f32, &Transform)> = (0..tournament_size)
-                    .map(|_| {
-                        let index = rng.gen_range(0..fitness_scores.len());
-                        &fitness_scores[index]
-                    })
-                    .collect();
+impl Registrar for GeneticAlgorithm {
+    fn name(&self) -> &str {
+        "GA"
+    }
 
-                // Select the best individual in the tournament
-                tournament
-                    .iter()
-                    .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap())
-                    .map(|(_, t)| (*t).clone()) // Dereference &Transform to Transform
-                    .unwrap()
+    fn register(
+        &self,
+        source: &[[f32; 3]],
+        target: &[[f32; 3]],
+        initial: &InitialGuess
+    ) -> Result<RegistrationResult, RegistrationError> {
+        if source.is_empty() || target.is_empty() {
+            return Err(RegistrationError::EmptyPointCloud);
+        }
+
+        // Selection and crossover draw from the main stream, the rest from one stream per individual
+        let streams = RandomStreams::new(self.seed);
+        let mut rng = streams.main();
+
+        // Initialize population
+        let mut population: Vec<Transform> = (0..self.population_size)
+            .into_par_iter()
+            .map(|i| {
+                let mut rng = streams.stream(0, i);
+                // The initial guess itself is the first individual
+                if i == 0 { initial.pose } else { initial.sample(&mut rng) }
             })
             .collect();
 
-        // Crossover (combine parents to create new offspring)
-        let mut new_population = Vec::with_capacity(population_size);
-        while new_population.len() < population_size {
-            let parent1 = &selected[rng.gen_range(0..selected.len())];
-            let parent2 = &selected[rng.gen_range(0..selected.len())];
-
-            let child = Transform {
-                translation: Vec3::new(
-                    (parent1.translation.x + parent2.translation.x) / 2.0,
-                    (parent1.translation.y + parent2.translation.y) / 2.0,
-                    (parent1.translation.z + parent2.translation.z) / 2.0,
-                ),
-                rotation: parent1.rotation.slerp(parent2.rotation, 0.5),
-                ..Default::default()
-            };
+        let context = FitnessContext::with_options(source, target, &self.correspondences);
+
+        // Elites keep their fitness, they are not evaluated again
+        let mut elites: Vec<(Transform, f32)> = Vec::new();
+
+        let mut best_transform = None;
+        let mut best_fitness = f32::INFINITY;
+        let mut no_improvement_counter = 0;
+        let mut mutation_step_size = self.mutation_step_size;
+        let mut history = Vec::new();
+        let mut termination = Termination::MaxIterations;
 
-            new_population.push(child);
+        for g in 0..self.generations {
+            // Evaluate fitness
+            let mut fitness_scores: Vec<(Transform, f32)> = population
+                .par_iter()
+                .map(|t| (*t, context.evaluate(t)))
+                .collect();
+            fitness_scores.append(&mut elites);
+
+            fitness_scores.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+            // Update the best transform
+            if fitness_scores[0].1 < best_fitness {
+                best_fitness = fitness_scores[0].1;
+                best_transform = Some(fitness_scores[0].0);
+                no_improvement_counter = 0; // Reset counter if improvement
+                if self.verbose { println!("Generation {} | Best fitness: {}", g, best_fitness); }
+            } else {
+                no_improvement_counter += 1;
+            }
+            history.push(best_fitness);
+
+            // Stop if no improvement in the last stopping_threshold generations
+            if no_improvement_counter >= self.stopping_threshold {
+                if self.verbose {
+                    println!("No improvement in the last {} generations. Stopping early.", self.stopping_threshold)
+                };
+                termination = Termination::Stagnated;
+                break;
+            }
+
+            // Check for convergence
+            if best_fitness < self.convergence_threshold {
+                termination = Termination::Converged;
+                break;
+            }
+
+            // Elitism: the best individuals survive unchanged
+            elites = fitness_scores.iter().take(self.elitism).copied().collect();
+
+            // Selection and crossover (combine parents to create new offspring)
+            let mut new_population = Vec::with_capacity(self.population_size - self.elitism);
+            while new_population.len() < self.population_size - self.elitism {
+                let parent1 = &fitness_scores[self.select(&mut rng, &fitness_scores)].0;
+                let parent2 = &fitness_scores[self.select(&mut rng, &fitness_scores)].0;
+
+                new_population.push(self.cross(&mut rng, parent1, parent2));
+            }
+
+            // Mutation (Gaussian perturbation, with a step size that decays over the generations)
+            let translation_step = mutation_step_size * initial.translation_radius;
+            let rotation_step = mutation_step_size * initial.rotation_radius;
+            new_population.par_iter_mut().enumerate().for_each(|(i, individual)| {
+                let mut rng = streams.stream(g + 1, i);
+                if rng.gen::<f32>() < self.mutation_rate {
+                    let mut gaussian = || rng.sample::<f32, _>(StandardNormal);
+                    let perturbation = PoseVector::new(
+                        Vec3::new(gaussian(), gaussian(), gaussian()) * translation_step,
+                        Vec3::new(gaussian(), gaussian(), gaussian()) * rotation_step
+                    );
+                    *individual = perturbation.apply(individual);
+                }
+            });
+            mutation_step_size *= self.mutation_decay;
+
+            population = new_population;
         }
 
-        // Mutation (randomly perturb new population)
-        new_population.par_iter_mut().for_each(|individual| {
-            let mut rng = thread_rng();
-            if rng.gen::<f32>() < mutation_rate {
-                individual.translation += Vec3::new(
-                    rng.gen_range(-0.1..0.1),
-                    rng.gen_range(-0.1..0.1),
-                    rng.gen_range(-0.1..0.1),
-                );
-                individual.rotation *= Quat::from_euler(
-                    bevy::math::EulerRot::XYZ,
-                    rng.gen_range(-0.1..0.1),
-                    rng.gen_range(-0.1..0.1),
-                    rng.gen_range(-0.1..0.1),
-                );
+        match best_transform {
+            Some(t) => Ok(RegistrationResult {
+                transform: t,
+                fitness: best_fitness,
+                iterations: history.len(),
+                evaluations: context.evaluations(),
+                termination,
+                overlap: context.overlap(&t),
+                seed: Some(streams.seed()),
+                history
+            }),
+            None => Err(RegistrationError::NotConverged { solver: self.name().to_string() }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_chacha::ChaCha8Rng;
+    use crate::solvers::fixtures::{assert_recovers_motion, clouds, initial_guess};
+
+    // Population sorted from best to worst, with an individual without correspondences last
+    fn ranked() -> Vec<(Transform, f32)> {
+        [0.1, 0.2, 0.4, 0.8, 1.6, f32::INFINITY].iter().map(|&fitness| (Transform::IDENTITY, fitness)).collect()
+    }
+
+    fn counts(ga: &GeneticAlgorithm) -> Vec<usize> {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let ranked = ranked();
+        let mut counts = vec![0; ranked.len()];
+        for _ in 0..6000 {
+            counts[ga.select(&mut rng, &ranked)] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn spin_follows_the_weights() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        assert!((0..100).all(|_| spin(&mut rng, &[0.0, 1.0, 0.0]) == 1));
+        assert!((0..100).all(|_| spin(&mut rng, &[0.0, 0.0, 0.0]) < 3));
+    }
+
+    #[test]
+    fn selections_prefer_the_best() {
+        for selection in [Selection::Tournament, Selection::Roulette, Selection::Rank] {
+            let counts = counts(&GeneticAlgorithm { selection, ..Default::default() });
+            assert!(counts.windows(2).all(|pair| pair[0] > pair[1]), "{:?}: {:?}", selection, counts);
+        }
+        // Any of the best half, and never the individual without correspondences with the roulette
+        let truncation = counts(&GeneticAlgorithm { selection: Selection::Truncation, ..Default::default() });
+        assert!(truncation[..3].iter().all(|&count| (1800..2200).contains(&count)), "{:?}", truncation);
+        assert_eq!(truncation[3..], [0, 0, 0]);
+        assert_eq!(counts(&GeneticAlgorithm { selection: Selection::Roulette, ..Default::default() })[5], 0);
+        // A tournament of one is a uniform choice
+        let uniform = counts(&GeneticAlgorithm { tournament_size: 1, ..Default::default() });
+        assert!(uniform.iter().all(|&count| (800..1200).contains(&count)), "{:?}", uniform);
+    }
+
+    #[test]
+    fn crossovers_stay_near_the_parents() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let parent1 = Transform::IDENTITY;
+        let parent2 = Transform::from_xyz(1.0, -2.0, 0.5);
+        for _ in 0..100 {
+            let child = GeneticAlgorithm { crossover: Crossover::Uniform, ..Default::default() }
+                .cross(&mut rng, &parent1, &parent2);
+            for (gene, other) in child.translation.to_array().into_iter().zip(parent2.translation.to_array()) {
+                assert!(gene == 0.0 || gene == other);
+            }
+
+            // Without extension, every gene is between the parents
+            let child = GeneticAlgorithm { crossover: Crossover::Blx { alpha: 0.0 }, ..Default::default() }
+                .cross(&mut rng, &parent1, &parent2);
+            assert!(child.translation.cmpge(Vec3::new(0.0, -2.0, 0.0)).all());
+            assert!(child.translation.cmple(Vec3::new(1.0, 0.0, 0.5)).all());
+
+            // With a large eta, every SBX gene is close to either parent
+            let child = GeneticAlgorithm { crossover: Crossover::Sbx { eta: 1000.0 }, ..Default::default() }
+                .cross(&mut rng, &parent1, &parent2);
+            for (gene, other) in child.translation.to_array().into_iter().zip(parent2.translation.to_array()) {
+                assert!(gene.abs().min((gene - other).abs()) < 0.05 * other.abs(), "{} {}", gene, other);
             }
-        });
+        }
+    }
+
+    #[test]
+    fn elites_are_not_evaluated_again() {
+        let (source, target) = clouds();
+        let ga = GeneticAlgorithm {
+            population_size: 20,
+            generations: 5,
+            elitism: 3,
+            convergence_threshold: 0.0,
+            seed: Some(1),
+            ..Default::default()
+        };
+        let result = ga.register(&source, &target, &initial_guess()).unwrap();
+        assert_eq!(result.evaluations, 20 + 4 * 17);
+        assert!(result.history.windows(2).all(|pair| pair[1] <= pair[0]));
+    }
 
-        population = new_population;
+    #[test]
+    fn recovers_a_small_motion() {
+        let (source, target) = clouds();
+        let ga = GeneticAlgorithm {
+            population_size: 40,
+            generations: 60,
+            convergence_threshold: 1e-6,
+            seed: Some(1),
+            ..Default::default()
+        };
+        let result = ga.register(&source, &target, &initial_guess()).unwrap();
+        assert_recovers_motion(&result, 1e-2, 2e-2);
     }
 
-    match best_transform {
-        Some(t) => Ok(t),
-        None => Err("Failed to find a suitable transformation.".to_string()),
+    #[test]
+    fn validates_the_population() {
+        assert!(GeneticAlgorithm::default().validate().is_ok());
+        assert!(GeneticAlgorithm { population_size: 1, ..Default::default() }.validate().is_err());
+        assert!(GeneticAlgorithm { elitism: 100, ..Default::default() }.validate().is_err());
+        assert!(GeneticAlgorithm { tournament_size: 0, ..Default::default() }.validate().is_err());
     }
 }
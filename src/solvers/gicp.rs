@@ -0,0 +1,195 @@
+use bevy::prelude::*;
+use nalgebra::{Matrix3, Matrix3x6, Matrix6, Point3, Quaternion, UnitQuaternion, Vector3, Vector6};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::error::RegistrationError;
+use crate::robust::{CorrespondenceOptions, Matcher};
+use crate::utils::{build_kd_tree, estimate_covariances};
+use super::{InitialGuess, Registrar, RegistrationResult, Termination};
+
+/// Generalized-ICP (plane-to-plane) parameters
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GeneralizedIcp {
+    pub max_iterations: usize,
+    pub convergence_threshold: f32,
+    pub covariance_neighbours: usize, // Neighbours used to estimate the point covariances
+    pub covariance_epsilon: f32,      // Variance along the surface normal
+    pub correspondences: CorrespondenceOptions,
+    pub verbose: bool,
+}
+
+impl Default for GeneralizedIcp {
+    fn default() -> Self {
+        Self {
+            max_iterations: 100,
+            convergence_threshold: 0.5,
+            covariance_neighbours: 20,
+            covariance_epsilon: 0.001,
+            correspondences: CorrespondenceOptions::default(),
+            verbose: false,
+        }
+    }
+}
+
+impl GeneralizedIcp {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.covariance_neighbours < 3 {
+            return Err("covariance_neighbours must be at least 3 to estimate the covariances".to_string());
+        }
+        if self.covariance_epsilon <= 0.0 {
+            return Err("covariance_epsilon must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Registrar for GeneralizedIcp {
+    fn name(&self) -> &str {
+        "GICP"
+    }
+
+    fn register(
+        &self,
+        source: &[[f32; 3]],
+        target: &[[f32; 3]],
+        initial: &InitialGuess
+    ) -> Result<RegistrationResult, RegistrationError> {
+        if source.is_empty() || target.is_empty() {
+            return Err(RegistrationError::EmptyPointCloud);
+        }
+
+        let source_points: Vec<Point3<f32>> = source.iter().map(|&p| Point3::from(p)).collect();
+        let target_points: Vec<Point3<f32>> = target.iter().map(|&p| Point3::from(p)).collect();
+
+        // Covariances are computed once, in the frame of each point cloud
+        let source_tree = build_kd_tree(&source_points);
+        let target_tree = build_kd_tree(&target_points);
+        let source_covariances = estimate_covariances(
+            &source_points, &source_tree, self.covariance_neighbours, self.covariance_epsilon
+        );
+        let target_covariances = estimate_covariances(
+            &target_points, &target_tree, self.covariance_neighbours, self.covariance_epsilon
+        );
+        let matcher = Matcher::new(source, target, &self.correspondences);
+        let kernel = matcher.kernel();
+
+        // ICP is a local method: it starts from the initial pose and ignores the search radii
+        let mut transform = initial.pose;
+        let mut history = Vec::new();
+        let mut termination = Termination::MaxIterations;
+
+        for i in 0..self.max_iterations {
+            let rotation = Matrix3::from(UnitQuaternion::from_quaternion(Quaternion::new(
+                transform.rotation.w, transform.rotation.x, transform.rotation.y, transform.rotation.z
+            )));
+
+            // Terms of the Gauss-Newton system of the plane-to-plane Mahalanobis error, computed
+            // in parallel but summed in order, so the result does not depend on the threads
+            let terms: Vec<(Matrix6<f32>, Vector6<f32>)> = matcher
+                .find(&transform)
+                .par_iter()
+                .filter_map(|c| {
+                    let point = transform.transform_point(Vec3::from(source[c.source]));
+                    let point = Vector3::new(point.x, point.y, point.z);
+
+                    let combined = target_covariances[c.target]
+                        + rotation * source_covariances[c.source] * rotation.transpose();
+                    let information = combined.try_inverse()? * kernel.weight(c.squared_distance);
+                    let difference = target_points[c.target].coords - point;
+
+                    // Derivative of the difference with respect to a small rotation and translation
+                    let mut jacobian = Matrix3x6::<f32>::zeros();
+                    jacobian.fixed_view_mut::<3, 3>(0, 0).copy_from(&point.cross_matrix());
+                    jacobian.fixed_view_mut::<3, 3>(0, 3).copy_from(&(-Matrix3::identity()));
+
+                    let weighted = jacobian.transpose() * information;
+                    Some((weighted * jacobian, weighted * difference))
+                })
+                .collect();
+            let (hessian, gradient) = terms
+                .iter()
+                .fold((Matrix6::zeros(), Vector6::zeros()), |(hessian, gradient), (h, g)| (hessian + h, gradient + g));
+
+            let Some(step) = hessian.cholesky().map(|cholesky| -cholesky.solve(&gradient)) else {
+                // Degenerate geometry, e.g. a single plane, or every correspondence was rejected
+                termination = Termination::Stagnated;
+                break;
+            };
+
+            // Update transform
+            let increment_rotation = UnitQuaternion::from_scaled_axis(Vector3::new(step[0], step[1], step[2]));
+            let increment = Transform {
+                translation: Vec3::new(step[3], step[4], step[5]),
+                rotation: Quat::from_xyzw(
+                    increment_rotation.i, increment_rotation.j, increment_rotation.k, increment_rotation.w
+                ),
+                ..Default::default()
+            };
+            transform = increment.mul_transform(transform);
+            transform.rotation = transform.rotation.normalize();
+
+            // Check for convergence
+            let correspondences = matcher.find(&transform);
+            let mean_error = if correspondences.is_empty() {
+                f32::INFINITY
+            } else {
+                correspondences.iter().map(|c| c.squared_distance.sqrt()).sum::<f32>() / correspondences.len() as f32
+            };
+
+            // Mean loss, comparable with the fitness of the other solvers
+            history.push(matcher.score(&correspondences));
+
+            if self.verbose { println!("Iteration {} | Mean error {}", i, mean_error); }
+
+            if mean_error < self.convergence_threshold {
+                termination = Termination::Converged;
+                break;
+            }
+        }
+
+        Ok(RegistrationResult {
+            transform,
+            fitness: history.last().copied().unwrap_or(f32::INFINITY),
+            iterations: history.len(),
+            evaluations: history.len(), // One correspondence search per iteration
+            termination,
+            overlap: matcher.overlap(&matcher.find(&transform)),
+            seed: None,
+            history
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::solvers::fixtures::{assert_recovers_motion, clouds, initial_guess};
+
+    #[test]
+    fn recovers_a_small_motion() {
+        let (source, target) = clouds();
+        let gicp = GeneralizedIcp { convergence_threshold: 1e-4, ..Default::default() };
+        let result = gicp.register(&source, &target, &initial_guess()).unwrap();
+        assert_recovers_motion(&result, 1e-3, 1e-3);
+        assert_eq!(result.termination, Termination::Converged);
+    }
+
+    #[test]
+    fn is_deterministic() {
+        let (source, target) = clouds();
+        let gicp = GeneralizedIcp { max_iterations: 5, ..Default::default() };
+        let first = gicp.register(&source, &target, &initial_guess()).unwrap();
+        let second = gicp.register(&source, &target, &initial_guess()).unwrap();
+        assert_eq!(first.transform, second.transform);
+        assert_eq!(first.history, second.history);
+    }
+
+    #[test]
+    fn rejects_degenerate_covariances() {
+        assert!(GeneralizedIcp::default().validate().is_ok());
+        assert!(GeneralizedIcp { covariance_neighbours: 2, ..Default::default() }.validate().is_err());
+        assert!(GeneralizedIcp { covariance_epsilon: 0.0, ..Default::default() }.validate().is_err());
+    }
+}
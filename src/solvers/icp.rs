@@ -1,98 +1,330 @@
 use bevy::prelude::*;
-use nalgebra::{Matrix3, UnitQuaternion, Vector3};
-
-use crate::config::POSE2;
-
-pub fn iterative_closest_point(
-    source: &Vec<[f32; 3]>,
-    target: &Vec<[f32; 3]>,
-    max_iterations: usize,
-    convergence_threshold: f32,
-    verbose: bool
-) -> Result<Transform, String> {
-    if source.is_empty() || target.is_empty() {
-        return Err("Source or target point cloud is empty.".to_string());
+use nalgebra::{Matrix3, Matrix6, Point3, UnitQuaternion, Vector3, Vector6};
+use serde::{Deserialize, Serialize};
+
+use crate::error::RegistrationError;
+use crate::robust::{CorrespondenceOptions, Matcher};
+use super::{InitialGuess, Registrar, RegistrationResult, Termination};
+
+/// Point-to-point ICP parameters
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IterativeClosestPoint {
+    pub max_iterations: usize,
+    pub convergence_threshold: f32,
+    pub correspondences: CorrespondenceOptions,
+    pub verbose: bool,
+}
+
+impl Default for IterativeClosestPoint {
+    fn default() -> Self {
+        Self {
+            max_iterations: 100,
+            convergence_threshold: 0.5,
+            correspondences: CorrespondenceOptions::default(),
+            verbose: false,
+        }
     }
+}
+
+impl Registrar for IterativeClosestPoint {
+    fn name(&self) -> &str {
+        "ICP"
+    }
+
+    fn register(
+        &self,
+        source: &[[f32; 3]],
+        target: &[[f32; 3]],
+        initial: &InitialGuess
+    ) -> Result<RegistrationResult, RegistrationError> {
+        if source.is_empty() || target.is_empty() {
+            return Err(RegistrationError::EmptyPointCloud);
+        }
+
+        // ICP is a local method: it starts from the initial pose and ignores the search radii
+        let mut transform = initial.pose;
+        let source_points: Vec<Vec3> = source.iter().map(|&p| Vec3::from(p)).collect();
+        let target_points: Vec<Vector3<f32>> = target.iter().map(|&p| Vector3::from(p)).collect();
+
+        // The target does not move, so its k-d tree is built only once
+        let matcher = Matcher::new(source, target, &self.correspondences);
+        let kernel = matcher.kernel();
+
+        let mut history = Vec::new();
+        let mut termination = Termination::MaxIterations;
+
+        for i in 0..self.max_iterations {
+            // Find closest points in target for each point in source, without the outliers
+            let correspondences = matcher.find(&transform);
+            let pairs: Vec<(Vector3<f32>, Vector3<f32>, f32)> = correspondences
+                .iter()
+                .map(|c| {
+                    let point = transform.transform_point(source_points[c.source]);
+                    (Vector3::new(point.x, point.y, point.z), target_points[c.target], kernel.weight(c.squared_distance))
+                })
+                .collect();
+            let total_weight: f32 = pairs.iter().map(|(_, _, weight)| weight).sum();
+            if total_weight <= 0.0 {
+                // Every correspondence was rejected
+                termination = Termination::Stagnated;
+                break;
+            }
+
+            // Compute weighted centroids
+            let source_centroid = pairs.iter().map(|(src, _, w)| src * *w).sum::<Vector3<f32>>() / total_weight;
+            let target_centroid = pairs.iter().map(|(_, tgt, w)| tgt * *w).sum::<Vector3<f32>>() / total_weight;
 
-    let current_transform = POSE2;
-    let mut source_points: Vec<Vector3<f32>> = source.iter().map(|&p| {
-        let point = current_transform.transform_point(Vec3::new(p[0], p[1], p[2]));
-        Vector3::from([point.x, point.y, point.z])
-    }).collect();
-    let target_points: Vec<Vector3<f32>> = target.iter().map(|&p| Vector3::from(p)).collect();
+            // Compute weighted cross-covariance matrix
+            let mut covariance = Matrix3::zeros();
+            for (src, tgt, weight) in pairs.iter() {
+                covariance += (src - source_centroid) * (tgt - target_centroid).transpose() * *weight;
+            }
+
+            // Perform Singular Value Decomposition (SVD)
+            let svd = covariance.svd(true, true);
+            let u = svd.u.unwrap();
+            let v_t = svd.v_t.unwrap();
+
+            let rotation_matrix = v_t.transpose() * u.transpose();
+
+            // Ensure a proper rotation matrix (determinant must be 1)
+            let det = rotation_matrix.determinant();
+            let rotation_matrix = if det < 0.0 {
+                let mut u_fixed = u.clone();
+                u_fixed.column_mut(2).scale_mut(-1.0);
+                v_t.transpose() * u_fixed.transpose()
+            } else {
+                rotation_matrix
+            };
 
-    let mut transform = Transform::IDENTITY;
+            let rotation = UnitQuaternion::from_matrix(&rotation_matrix);
+            let translation = target_centroid - rotation * source_centroid;
 
-    for i in 0..max_iterations {
-        // Find closest points in target for each point in source
-        let mut closest_points = Vec::with_capacity(source_points.len());
-        for &src in &source_points {
-            if let Some(&closest) = target_points
+            // Update transform
+            let increment = Transform {
+                translation: Vec3::new(translation.x, translation.y, translation.z),
+                rotation: Quat::from_xyzw(rotation.i, rotation.j, rotation.k, rotation.w),
+                ..Default::default()
+            };
+            transform = increment.mul_transform(transform);
+            transform.rotation = transform.rotation.normalize();
+
+            // Distances of the moved source points to their correspondences
+            let squared_distances: Vec<f32> = pairs
                 .iter()
-                .min_by(|&&a, &&b| a.metric_distance(&src).partial_cmp(&b.metric_distance(&src)).unwrap())
-            {
-                closest_points.push(closest);
+                .map(|(src, tgt, _)| (rotation * src + translation - tgt).norm_squared())
+                .collect();
+
+            // Check for convergence
+            let mean_error = mean_distance(&squared_distances);
+
+            // Mean loss of the correspondences of this iteration after the update. They are not
+            // searched again, so it differs from the fitness of the other solvers after re-matching.
+            history.push(matcher.loss(&squared_distances));
+
+            if self.verbose { println!("Iteration {} | Mean error {}", i, mean_error); }
+
+            if mean_error < self.convergence_threshold {
+                termination = Termination::Converged;
+                break;
             }
         }
 
-        // Compute centroids
-        let source_centroid = source_points.iter().sum::<Vector3<f32>>() / source_points.len() as f32;
-        let target_centroid = closest_points.iter().sum::<Vector3<f32>>() / closest_points.len() as f32;
+        Ok(RegistrationResult {
+            transform,
+            fitness: history.last().copied().unwrap_or(f32::INFINITY),
+            iterations: history.len(),
+            evaluations: history.len(), // One correspondence search per iteration
+            termination,
+            overlap: matcher.overlap(&matcher.find(&transform)),
+            seed: None,
+            history
+        })
+    }
+}
 
-        // Center the points
-        let source_centered: Vec<Vector3<f32>> = source_points.iter().map(|p| p - source_centroid).collect();
-        let target_centered: Vec<Vector3<f32>> = closest_points.iter().map(|p| p - target_centroid).collect();
+/// Point-to-plane ICP parameters
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PointToPlaneIcp {
+    pub max_iterations: usize,
+    pub convergence_threshold: f32,
+    pub correspondences: CorrespondenceOptions,
+    pub verbose: bool,
+}
 
-        // Compute cross-covariance matrix
-        let mut covariance = Matrix3::zeros();
-        for (src, tgt) in source_centered.iter().zip(target_centered.iter()) {
-            covariance += src * tgt.transpose();
+impl Default for PointToPlaneIcp {
+    fn default() -> Self {
+        Self {
+            max_iterations: 100,
+            convergence_threshold: 0.5,
+            correspondences: CorrespondenceOptions::default(),
+            verbose: false,
         }
+    }
+}
 
-        // Perform Singular Value Decomposition (SVD)
-        let svd = covariance.svd(true, true);
-        let u = svd.u.unwrap();
-        let v_t = svd.v_t.unwrap();
-
-        let rotation_matrix = v_t.transpose() * u.transpose();
-
-        // Ensure a proper rotation matrix (determinant must be 1)
-        let det = rotation_matrix.determinant();
-        let rotation_matrix = if det < 0.0 {
-            let mut u_fixed = u.clone();
-            u_fixed.column_mut(2).scale_mut(-1.0);
-            v_t.transpose() * u_fixed.transpose()
-        } else {
-            rotation_matrix
-        };
-
-        let rotation = UnitQuaternion::from_matrix(&rotation_matrix);
-        let translation = target_centroid - rotation * source_centroid;
-
-        // Update transform
-        transform.translation = Vec3::new(translation.x, translation.y, translation.z);
-        transform.rotation = Quat::from_xyzw(rotation.i, rotation.j, rotation.k, rotation.w);
-
-        // Apply transform to source points
-        source_points = source_points
-            .iter()
-            .map(|p| rotation * p + translation)
-            .collect();
-
-        // Check for convergence
-        let mean_error: f32 = source_points
-            .iter()
-            .zip(closest_points.iter())
-            .map(|(src, tgt)| src.metric_distance(tgt))
-            .sum::<f32>()
-            / source_points.len() as f32;
-
-        if verbose { println!("Iteration {} | Mean error {}", i, mean_error); }
-
-        if mean_error < convergence_threshold {
-            break;
+impl PointToPlaneIcp {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.correspondences.normal_neighbours < 3 {
+            return Err("normal_neighbours must be at least 3 to estimate the normals".to_string());
         }
+        Ok(())
     }
+}
+
+impl Registrar for PointToPlaneIcp {
+    fn name(&self) -> &str {
+        "PlaneICP"
+    }
+
+    fn register(
+        &self,
+        source: &[[f32; 3]],
+        target: &[[f32; 3]],
+        initial: &InitialGuess
+    ) -> Result<RegistrationResult, RegistrationError> {
+        if source.is_empty() || target.is_empty() {
+            return Err(RegistrationError::EmptyPointCloud);
+        }
 
-    Ok(transform)
-}
\ No newline at end of file
+        // ICP is a local method: it starts from the initial pose and ignores the search radii
+        let mut transform = initial.pose;
+        let source_points: Vec<Vec3> = source.iter().map(|&p| Vec3::from(p)).collect();
+        let target_points: Vec<Point3<f32>> = target.iter().map(|&p| Point3::from(p)).collect();
+
+        // The target does not move, so its normals are estimated only once, from the
+        // normal_neighbours of the correspondence options
+        let matcher = Matcher::new(source, target, &self.correspondences);
+        let target_normals = matcher.target_normals();
+        let kernel = matcher.kernel();
+
+        let mut history = Vec::new();
+        let mut termination = Termination::MaxIterations;
+
+        for i in 0..self.max_iterations {
+            // Find closest points in target for each point in source, without the outliers
+            let correspondences: Vec<_> = matcher
+                .find(&transform)
+                .iter()
+                .map(|c| {
+                    let point = transform.transform_point(source_points[c.source]);
+                    (
+                        Vector3::new(point.x, point.y, point.z),
+                        target_points[c.target].coords,
+                        target_normals[c.target],
+                        kernel.weight(c.squared_distance)
+                    )
+                })
+                .collect();
+
+            // Linearize the rotation and solve the weighted normal equations of the point-to-plane error
+            let mut ata = Matrix6::<f32>::zeros();
+            let mut atb = Vector6::<f32>::zeros();
+            for (src, tgt, normal, weight) in correspondences.iter() {
+                let cross = src.cross(normal);
+                let row = Vector6::new(cross.x, cross.y, cross.z, normal.x, normal.y, normal.z);
+                ata += row * row.transpose() * *weight;
+                atb += row * (tgt - src).dot(normal) * *weight;
+            }
+            let Some(x) = ata.cholesky().map(|cholesky| cholesky.solve(&atb)) else {
+                // Degenerate geometry, e.g. a single plane, or every correspondence was rejected
+                termination = Termination::Stagnated;
+                break;
+            };
+
+            let rotation = UnitQuaternion::from_scaled_axis(Vector3::new(x[0], x[1], x[2]));
+            let translation = Vector3::new(x[3], x[4], x[5]);
+
+            // Update transform
+            let increment = Transform {
+                translation: Vec3::new(translation.x, translation.y, translation.z),
+                rotation: Quat::from_xyzw(rotation.i, rotation.j, rotation.k, rotation.w),
+                ..Default::default()
+            };
+            transform = increment.mul_transform(transform);
+            transform.rotation = transform.rotation.normalize();
+
+            // Distances of the moved source points to their correspondences
+            let squared_distances: Vec<f32> = correspondences
+                .iter()
+                .map(|(src, tgt, _, _)| (rotation * src + translation - tgt).norm_squared())
+                .collect();
+
+            // Check for convergence
+            let mean_error = mean_distance(&squared_distances);
+
+            // Mean loss of the correspondences of this iteration after the update. They are not
+            // searched again, so it differs from the fitness of the other solvers after re-matching.
+            history.push(matcher.loss(&squared_distances));
+
+            if self.verbose { println!("Iteration {} | Mean error {}", i, mean_error); }
+
+            if mean_error < self.convergence_threshold {
+                termination = Termination::Converged;
+                break;
+            }
+        }
+
+        Ok(RegistrationResult {
+            transform,
+            fitness: history.last().copied().unwrap_or(f32::INFINITY),
+            iterations: history.len(),
+            evaluations: history.len(), // One correspondence search per iteration
+            termination,
+            overlap: matcher.overlap(&matcher.find(&transform)),
+            seed: None,
+            history
+        })
+    }
+}
+
+// Mean distance of the correspondences, infinite if there are none
+fn mean_distance(squared_distances: &[f32]) -> f32 {
+    if squared_distances.is_empty() {
+        return f32::INFINITY;
+    }
+    squared_distances.iter().map(|d| d.sqrt()).sum::<f32>() / squared_distances.len() as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::solvers::fixtures::{assert_recovers_motion, clouds, initial_guess};
+
+    #[test]
+    fn point_to_point_recovers_a_small_motion() {
+        let (source, target) = clouds();
+        let icp = IterativeClosestPoint { convergence_threshold: 1e-4, ..Default::default() };
+        let result = icp.register(&source, &target, &initial_guess()).unwrap();
+        assert_recovers_motion(&result, 1e-3, 1e-3);
+        assert_eq!(result.termination, Termination::Converged);
+    }
+
+    #[test]
+    fn point_to_plane_recovers_a_small_motion() {
+        let (source, target) = clouds();
+        let icp = PointToPlaneIcp { convergence_threshold: 1e-4, ..Default::default() };
+        let result = icp.register(&source, &target, &initial_guess()).unwrap();
+        assert_recovers_motion(&result, 1e-3, 1e-3);
+        assert_eq!(result.termination, Termination::Converged);
+    }
+
+    #[test]
+    fn point_to_plane_needs_three_neighbours() {
+        let mut icp = PointToPlaneIcp::default();
+        assert!(icp.validate().is_ok());
+        icp.correspondences.normal_neighbours = 2;
+        assert!(icp.validate().is_err());
+    }
+
+    #[test]
+    fn empty_clouds_are_rejected() {
+        let (source, _) = clouds();
+        let result = IterativeClosestPoint::default().register(&source, &[], &initial_guess());
+        assert!(matches!(result, Err(RegistrationError::EmptyPointCloud)));
+        let result = PointToPlaneIcp::default().register(&[], &source, &initial_guess());
+        assert!(matches!(result, Err(RegistrationError::EmptyPointCloud)));
+    }
+}
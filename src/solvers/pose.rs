@@ -0,0 +1,182 @@
+use std::ops::{Add, Mul, Neg, Sub};
+use bevy::math::{Quat, Vec3};
+use bevy::prelude::Transform;
+use nalgebra::Vector6;
+
+/// Pose, or pose difference, as a 6-vector: translation and rotation vector (axis × angle).
+///
+/// Rotations live on SO(3) and are moved through its exponential and logarithm maps, so the
+/// vectors can be added, subtracted and scaled like plain numbers without Euler-angle wrap-around
+/// or gimbal lock. Translations are kept apart from the rotation (R³ × SO(3)), which keeps them
+/// in world coordinates, comparable with the search radii.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct PoseVector {
+    pub translation: Vec3,
+    pub rotation: Vec3,
+}
+
+impl PoseVector {
+    pub const ZERO: PoseVector = PoseVector { translation: Vec3::ZERO, rotation: Vec3::ZERO };
+
+    pub fn new(translation: Vec3, rotation: Vec3) -> Self {
+        PoseVector { translation, rotation }
+    }
+
+    /// Pose vector of a transform, relative to the identity
+    pub fn log(transform: &Transform) -> Self {
+        PoseVector { translation: transform.translation, rotation: log_rotation(transform.rotation) }
+    }
+
+    /// Transform of a pose vector, relative to the identity
+    pub fn exp(&self) -> Transform {
+        Transform {
+            translation: self.translation,
+            rotation: Quat::from_scaled_axis(self.rotation),
+            ..Default::default()
+        }
+    }
+
+    /// Difference `to ⊖ from`: the vector that moves `from` to `to`, with the rotation in the frame of `from`
+    pub fn between(from: &Transform, to: &Transform) -> Self {
+        PoseVector {
+            translation: to.translation - from.translation,
+            rotation: log_rotation(from.rotation.inverse() * to.rotation),
+        }
+    }
+
+    /// Sum `pose ⊕ self`: moves the pose by this vector
+    pub fn apply(&self, pose: &Transform) -> Transform {
+        Transform {
+            translation: pose.translation + self.translation,
+            rotation: (pose.rotation * Quat::from_scaled_axis(self.rotation)).normalize(),
+            ..Default::default()
+        }
+    }
+
+    /// Translation and rotation components multiplied separately
+    pub fn scale(&self, translation: f32, rotation: f32) -> Self {
+        PoseVector { translation: self.translation * translation, rotation: self.rotation * rotation }
+    }
+
+    pub fn to_array(self) -> [f32; 6] {
+        [
+            self.translation.x, self.translation.y, self.translation.z,
+            self.rotation.x, self.rotation.y, self.rotation.z
+        ]
+    }
+
+    pub fn from_array(values: [f32; 6]) -> Self {
+        PoseVector {
+            translation: Vec3::new(values[0], values[1], values[2]),
+            rotation: Vec3::new(values[3], values[4], values[5]),
+        }
+    }
+}
+
+/// Distance between the translations and angle (radians) of the rotation from one pose to the other
+pub fn distance(a: &Transform, b: &Transform) -> (f32, f32) {
+    (a.translation.distance(b.translation), log_rotation(a.rotation.inverse() * b.rotation).length())
+}
+
+// Rotation vector of a quaternion, with an angle in [0, π]
+fn log_rotation(rotation: Quat) -> Vec3 {
+    // q and -q are the same rotation, the one with w >= 0 gives the shortest angle
+    let rotation = if rotation.w < 0.0 { -rotation } else { rotation };
+    let axis = Vec3::new(rotation.x, rotation.y, rotation.z);
+    let sin_half_angle = axis.length();
+    if sin_half_angle < 1e-7 {
+        // First order approximation near the identity
+        return axis * 2.0;
+    }
+    axis * (2.0 * sin_half_angle.atan2(rotation.w) / sin_half_angle)
+}
+
+impl Add for PoseVector {
+    type Output = PoseVector;
+
+    fn add(self, other: PoseVector) -> PoseVector {
+        PoseVector::new(self.translation + other.translation, self.rotation + other.rotation)
+    }
+}
+
+impl Sub for PoseVector {
+    type Output = PoseVector;
+
+    fn sub(self, other: PoseVector) -> PoseVector {
+        PoseVector::new(self.translation - other.translation, self.rotation - other.rotation)
+    }
+}
+
+impl Mul<f32> for PoseVector {
+    type Output = PoseVector;
+
+    fn mul(self, factor: f32) -> PoseVector {
+        PoseVector::new(self.translation * factor, self.rotation * factor)
+    }
+}
+
+impl Neg for PoseVector {
+    type Output = PoseVector;
+
+    fn neg(self) -> PoseVector {
+        PoseVector::new(-self.translation, -self.rotation)
+    }
+}
+
+impl From<Vector6<f32>> for PoseVector {
+    fn from(vector: Vector6<f32>) -> Self {
+        PoseVector::from_array(vector.into())
+    }
+}
+
+impl From<PoseVector> for Vector6<f32> {
+    fn from(vector: PoseVector) -> Self {
+        Vector6::from(vector.to_array())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    fn assert_vector_eq(a: PoseVector, b: PoseVector) {
+        assert!(a.translation.abs_diff_eq(b.translation, 1e-4), "{:?} != {:?}", a, b);
+        assert!(a.rotation.abs_diff_eq(b.rotation, 1e-3), "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn log_inverts_exp() {
+        let axis = Vec3::new(1.0, -2.0, 0.5).normalize();
+        let translation = Vec3::new(0.3, -1.2, 4.0);
+        for angle in [0.0, 1e-6, 1e-3, 0.5, 2.0, PI - 1e-2, PI - 1e-3] {
+            let vector = PoseVector::new(translation, axis * angle);
+            assert_vector_eq(PoseVector::log(&vector.exp()), vector);
+        }
+        assert_eq!(PoseVector::log(&PoseVector::ZERO.exp()), PoseVector::ZERO);
+    }
+
+    #[test]
+    fn log_takes_the_shortest_angle() {
+        // A rotation past π is the opposite rotation by less than π
+        let vector = PoseVector::new(Vec3::ZERO, Vec3::Z * (PI + 0.5));
+        assert_vector_eq(PoseVector::log(&vector.exp()), PoseVector::new(Vec3::ZERO, Vec3::Z * (0.5 - PI)));
+    }
+
+    #[test]
+    fn between_is_undone_by_apply() {
+        let poses = [
+            Transform::IDENTITY,
+            Transform::from_xyz(1.0, 2.0, -3.0).with_rotation(Quat::from_rotation_y(0.7)),
+            Transform::from_xyz(-0.5, 0.0, 0.25).with_rotation(Quat::from_axis_angle(Vec3::ONE.normalize(), 3.0)),
+            Transform::from_xyz(0.0, 1.0, 0.0).with_rotation(Quat::from_rotation_x(-PI + 1e-2)),
+        ];
+        for a in &poses {
+            for b in &poses {
+                let moved = PoseVector::between(a, b).apply(a);
+                let (translation, rotation) = distance(&moved, b);
+                assert!(translation < 1e-4 && rotation < 1e-3, "{:?} != {:?}", moved, b);
+            }
+        }
+    }
+}
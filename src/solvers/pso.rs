@@ -1,175 +1,339 @@
 use rayon::prelude::*;
-use rand::{thread_rng, Rng};
-use bevy::math::{Quat, Vec3};
+use rand::Rng;
 use bevy::prelude::Transform;
+use serde::{Deserialize, Serialize};
 
-use crate::utils::fitness;
-
-pub fn particle_swarm_optimization(
-    source: &Vec<[f32; 3]>,
-    target: &Vec<[f32; 3]>,
-    population_size: usize,
-    iterations: usize,
-    constriction_factor: f32,
-    inertia_weight: f32,
-    cognitive_weight: f32,
-    social_weight: f32,
-    initial_weight: f32,
-    convergence_threshold: f32,
-    verbose: bool,
-) -> Result<Transform, String> {
-    if source.is_empty() || target.is_empty() {
-        return Err("Source or target point cloud is empty.".to_string());
-    }
+use crate::utils::FitnessContext;
+use crate::error::RegistrationError;
+use crate::robust::CorrespondenceOptions;
+use super::pose::PoseVector;
+use super::random::RandomStreams;
+use super::{InitialGuess, Registrar, RegistrationResult, Termination};
 
-    let mut rng = thread_rng();
+/// Particles whose best position attracts each particle
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Topology {
+    Global,     // The whole swarm
+    Ring,       // The particles before and after in the swarm
+    VonNeumann, // The particles above, below, left and right in a toroidal grid
+}
 
-    // Preprocess points into Vec3 for easier calculations
-    let source_points: Vec<Vec3> = source.iter().map(|&p| Vec3::from(p)).collect();
-    let target_points: Vec<Vec3> = target.iter().map(|&p| Vec3::from(p)).collect();
+/// Particle swarm optimization parameters
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ParticleSwarmOptimization {
+    pub population_size: usize,
+    pub iterations: usize,
+    pub topology: Topology,
+    pub constriction_factor: f32,
+    pub inertia_weight: f32,       // Inertia of the first iteration
+    pub final_inertia_weight: f32, // Inertia of the last iteration, linearly interpolated in between
+    pub max_velocity: Option<f32>, // Relative to the search radii
+    pub cognitive_weight: f32,
+    pub social_weight: f32,
+    pub initial_weight: f32,
+    pub convergence_threshold: f32,
+    pub correspondences: CorrespondenceOptions,
+    pub seed: Option<u64>,
+    pub verbose: bool,
+}
 
-    // Particle representation
-    struct Particle {
-        position: Transform,
-        velocity: Transform,
-        best_position: Transform,
-        best_fitness: f32,
-        initial_position: Transform
+impl Default for ParticleSwarmOptimization {
+    fn default() -> Self {
+        Self {
+            population_size: 100,
+            iterations: 100,
+            topology: Topology::Global,
+            constriction_factor: 0.7298,
+            inertia_weight: 1.0,
+            final_inertia_weight: 1.0,
+            max_velocity: None,
+            cognitive_weight: 2.1,
+            social_weight: 1.2,
+            initial_weight: 0.0,
+            convergence_threshold: 0.5,
+            correspondences: CorrespondenceOptions::default(),
+            seed: None,
+            verbose: false,
+        }
     }
+}
 
-    // Initialize the particle swarm
-    let mut particles: Vec<Particle> = (0..population_size)
-        .map(|_| {
-            let position = Transform {
-                translation: Vec3::new(
-                    rng.gen_range(-1.0..1.0),
-                    rng.gen_range(-1.0..1.0),
-                    rng.gen_range(-1.0..1.0),
-                ),
-                rotation: Quat::from_euler(
-                    bevy::math::EulerRot::XYZ,
-                    rng.gen_range(-std::f32::consts::PI..std::f32::consts::PI),
-                    rng.gen_range(-std::f32::consts::PI..std::f32::consts::PI),
-                    rng.gen_range(-std::f32::consts::PI..std::f32::consts::PI),
-                ),
-                ..Default::default()
-            };
+impl ParticleSwarmOptimization {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.population_size == 0 {
+            return Err("the swarm needs at least 1 particle".to_string());
+        }
+        if self.max_velocity.is_some_and(|velocity| velocity <= 0.0) {
+            return Err("max_velocity must be positive".to_string());
+        }
+        Ok(())
+    }
+}
 
-            Particle {
-                position,
-                velocity: Transform {
-                    translation: Vec3::ZERO,
-                    rotation: Quat::IDENTITY,
-                    ..Default::default()
-                },
-                best_position: position,
-                best_fitness: f32::INFINITY,
-                initial_position: position.clone()
-            }
-        })
-        .collect();
-
-    // Global best particle
-    let mut global_best_position = Transform::default();
-    let mut global_best_fitness = f32::INFINITY;
-
-    for i in 0..iterations {
-        // Evaluate particles in parallel
-        particles
-            .par_iter_mut()
-            .for_each(|particle| {
-                // Evaluate fitness
-                let current_fitness = fitness(&particle.position, &source_points, &target_points);
-
-                // Update personal best
-                if current_fitness < particle.best_fitness {
-                    particle.best_fitness = current_fitness;
-                    particle.best_position = particle.position;
-                }
-            });
-
-        // Find the global best particle (reduce operation)
-        let (local_best_position, local_best_fitness) = particles
-            .par_iter()
-            .map(|particle| (particle.best_position, particle.best_fitness))
-            .reduce(
-                || (Transform::default(), f32::INFINITY),
-                |acc, next| if next.1 < acc.1 { next } else { acc },
-            );
-
-        // Update global best if a better fitness is found
-        if local_best_fitness < global_best_fitness {
-            global_best_fitness = local_best_fitness;
-            global_best_position = local_best_position;
-
-            if verbose && i != 0 {
-                println!("Iteration {} | Best fitness: {}", i, global_best_fitness);
-            }
+// Indices of the particles in the neighbourhood of every particle, itself included
+fn neighbourhoods(topology: Topology, size: usize) -> Vec<Vec<usize>> {
+    match topology {
+        Topology::Global => vec![(0..size).collect(); size],
+        Topology::Ring => (0..size)
+            .map(|i| vec![(i + size - 1) % size, i, (i + 1) % size])
+            .collect(),
+        Topology::VonNeumann => {
+            // Grid as square as possible, the last row may be incomplete
+            let columns = ((size as f32).sqrt().floor() as usize).max(1);
+            (0..size)
+                .map(|i| {
+                    let row_start = i - i % columns;
+                    let row_length = columns.min(size - row_start);
+                    let column = i - row_start;
+                    vec![
+                        i,
+                        row_start + (column + row_length - 1) % row_length, // Left
+                        row_start + (column + 1) % row_length,              // Right
+                        (i + size - columns % size) % size,                 // Above
+                        (i + columns) % size                                // Below
+                    ]
+                })
+                .collect()
         }
+    }
+}
 
-        // Check for convergence
-        if global_best_fitness < convergence_threshold {
-            break;
+impl Registrar for ParticleSwarmOptimization {
+    fn name(&self) -> &str {
+        "PSO"
+    }
+
+    fn register(
+        &self,
+        source: &[[f32; 3]],
+        target: &[[f32; 3]],
+        initial: &InitialGuess
+    ) -> Result<RegistrationResult, RegistrationError> {
+        if source.is_empty() || target.is_empty() {
+            return Err(RegistrationError::EmptyPointCloud);
         }
 
-        // Update velocity and position in parallel
-        particles
-            .par_iter_mut()
-            .for_each(|particle| {
-                let mut thread_rng = thread_rng();
+        // The swarm is initialized from the main stream, the velocities from one stream per particle
+        let streams = RandomStreams::new(self.seed);
+        let mut rng = streams.main();
 
-                let inertia = particle.velocity.translation * inertia_weight;
+        let context = FitnessContext::with_options(source, target, &self.correspondences);
 
-                let cognitive = (particle.best_position.translation - particle.position.translation)
-                    * cognitive_weight
-                    * thread_rng.gen::<f32>();
+        // Particle representation
+        struct Particle {
+            position: Transform,
+            velocity: PoseVector, // In the tangent space of the position
+            best_position: Transform,
+            best_fitness: f32,
+            initial_position: Transform
+        }
 
-                let social = (global_best_position.translation - particle.position.translation)
-                    * social_weight
-                    * thread_rng.gen::<f32>();
+        // Initialize the particle swarm
+        let mut particles: Vec<Particle> = (0..self.population_size)
+            .map(|i| {
+                // The initial guess itself is the first individual
+                let position = if i == 0 { initial.pose } else { initial.sample(&mut rng) };
 
-                let initial_influence = (particle.initial_position.translation
-                    - particle.position.translation)
-                    * initial_weight
-                    * thread_rng.gen::<f32>();
+                Particle {
+                    position,
+                    velocity: PoseVector::ZERO,
+                    best_position: position,
+                    best_fitness: f32::INFINITY,
+                    initial_position: position.clone()
+                }
+            })
+            .collect();
 
-                particle.velocity.translation = constriction_factor * (
-                    inertia + cognitive + social + initial_influence
-                );
+        let neighbourhoods = neighbourhoods(self.topology, self.population_size);
 
-                particle.position.translation += particle.velocity.translation;
+        // Global best particle
+        let mut global_best_position = Transform::default();
+        let mut global_best_fitness = f32::INFINITY;
+        let mut history = Vec::new();
+        let mut termination = Termination::MaxIterations;
 
-                // Update rotation using SLERP for smoothness
-                let rotation_inertia = particle.velocity.rotation.slerp(Quat::IDENTITY, inertia_weight);
+        for i in 0..self.iterations {
+            // Evaluate particles in parallel
+            particles
+                .par_iter_mut()
+                .for_each(|particle| {
+                    // Evaluate fitness
+                    let current_fitness = context.evaluate(&particle.position);
 
-                // Calculate rotation cognitive component
-                let rotation_cognitive = particle.position.rotation.slerp(
-                    particle.best_position.rotation,
-                    thread_rng.gen::<f32>() * cognitive_weight,
-                );
+                    // Update personal best
+                    if current_fitness < particle.best_fitness {
+                        particle.best_fitness = current_fitness;
+                        particle.best_position = particle.position;
+                    }
+                });
 
-                // Calculate rotation social component
-                let rotation_social = particle.position.rotation.slerp(
-                    global_best_position.rotation,
-                    thread_rng.gen::<f32>() * social_weight,
+            // Find the global best particle, sequentially so that ties are broken the same way every run
+            let (local_best_position, local_best_fitness) = particles
+                .iter()
+                .map(|particle| (particle.best_position, particle.best_fitness))
+                .fold(
+                    (Transform::default(), f32::INFINITY),
+                    |acc, next| if next.1 < acc.1 { next } else { acc },
                 );
 
-                // Calculate rotation initial influence
-                let rotation_initial = particle.position.rotation.slerp(
-                    particle.initial_position.rotation,
-                    thread_rng.gen::<f32>() * initial_weight,
-                );
+            // Update global best if a better fitness is found
+            if local_best_fitness < global_best_fitness {
+                global_best_fitness = local_best_fitness;
+                global_best_position = local_best_position;
+
+                if self.verbose && i != 0 {
+                    println!("Iteration {} | Best fitness: {}", i, global_best_fitness);
+                }
+            }
+            history.push(global_best_fitness);
+
+            // Check for convergence
+            if global_best_fitness < self.convergence_threshold {
+                termination = Termination::Converged;
+                break;
+            }
+
+            // Best position found in the neighbourhood of every particle
+            let neighbourhood_best: Vec<Transform> = neighbourhoods
+                .iter()
+                .map(|neighbourhood| {
+                    let best = neighbourhood
+                        .iter()
+                        .min_by(|&&a, &&b| particles[a].best_fitness.total_cmp(&particles[b].best_fitness))
+                        .unwrap();
+                    particles[*best].best_position
+                })
+                .collect();
+
+            // Inertia decreasing linearly from the first to the last iteration
+            let progress = if self.iterations > 1 { i as f32 / (self.iterations - 1) as f32 } else { 0.0 };
+            let inertia_weight = self.inertia_weight + (self.final_inertia_weight - self.inertia_weight) * progress;
 
-                particle.velocity.rotation = rotation_inertia * rotation_cognitive * rotation_social * rotation_initial;
+            // Update velocity and position in parallel
+            particles
+                .par_iter_mut()
+                .zip(neighbourhood_best.par_iter())
+                .enumerate()
+                .for_each(|(index, (particle, neighbourhood_best))| {
+                    let mut rng = streams.stream(i + 1, index);
+
+                    let inertia = particle.velocity * inertia_weight;
+
+                    let cognitive = PoseVector::between(&particle.position, &particle.best_position)
+                        * self.cognitive_weight
+                        * rng.gen::<f32>();
+
+                    let social = PoseVector::between(&particle.position, neighbourhood_best)
+                        * self.social_weight
+                        * rng.gen::<f32>();
+
+                    let initial_influence = PoseVector::between(&particle.position, &particle.initial_position)
+                        * self.initial_weight
+                        * rng.gen::<f32>();
+
+                    particle.velocity = (inertia + cognitive + social + initial_influence) * self.constriction_factor;
+
+                    // Velocity clamping, separately for the translation and the rotation
+                    if let Some(max_velocity) = self.max_velocity {
+                        particle.velocity.translation = particle.velocity.translation
+                            .clamp_length_max(max_velocity * initial.translation_radius);
+                        particle.velocity.rotation = particle.velocity.rotation
+                            .clamp_length_max(max_velocity * initial.rotation_radius);
+                    }
+
+                    particle.position = particle.velocity.apply(&particle.position);
+                });
+        }
+
+        // Return the best global transformation
+        if global_best_fitness < f32::INFINITY {
+            Ok(RegistrationResult {
+                transform: global_best_position,
+                fitness: global_best_fitness,
+                iterations: history.len(),
+                evaluations: context.evaluations(),
+                termination,
+                overlap: context.overlap(&global_best_position),
+                seed: Some(streams.seed()),
+                history
+            })
+        } else {
+            Err(RegistrationError::NotConverged { solver: self.name().to_string() })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::solvers::fixtures::{assert_recovers_motion, clouds, initial_guess};
+
+    fn pso() -> ParticleSwarmOptimization {
+        ParticleSwarmOptimization {
+            population_size: 20,
+            iterations: 60,
+            convergence_threshold: 1e-6,
+            seed: Some(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn neighbourhoods_of_a_grid() {
+        assert_eq!(neighbourhoods(Topology::Global, 3), vec![vec![0, 1, 2]; 3]);
+        assert_eq!(neighbourhoods(Topology::Ring, 4)[0], vec![3, 0, 1]);
+        assert_eq!(neighbourhoods(Topology::Ring, 4)[3], vec![2, 3, 0]);
+
+        // Self, left, right, above and below in a toroidal 3x3 grid
+        let grid = neighbourhoods(Topology::VonNeumann, 9);
+        assert_eq!(grid[4], vec![4, 3, 5, 1, 7]);
+        assert_eq!(grid[0], vec![0, 2, 1, 6, 3]);
+        assert_eq!(grid[8], vec![8, 7, 6, 5, 2]);
+    }
+
+    #[test]
+    fn neighbourhoods_of_an_incomplete_grid() {
+        for size in [1, 2, 5, 10, 17] {
+            for topology in [Topology::Global, Topology::Ring, Topology::VonNeumann] {
+                for (i, neighbourhood) in neighbourhoods(topology, size).iter().enumerate() {
+                    assert!(neighbourhood.contains(&i));
+                    assert!(neighbourhood.iter().all(|&j| j < size), "{:?} {}: {:?}", topology, size, neighbourhood);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_topology_recovers_a_small_motion() {
+        let (source, target) = clouds();
+        for topology in [Topology::Global, Topology::Ring, Topology::VonNeumann] {
+            let pso = ParticleSwarmOptimization {
+                topology,
+                inertia_weight: 0.9,
+                final_inertia_weight: 0.4,
+                max_velocity: Some(0.2),
+                ..pso()
+            };
+            let result = pso.register(&source, &target, &initial_guess()).unwrap();
+            assert_recovers_motion(&result, 1e-2, 2e-2);
+        }
+    }
 
-                particle.position.rotation *= particle.velocity.rotation;
-            });
+    #[test]
+    fn is_reproducible_from_the_seed() {
+        let (source, target) = clouds();
+        let pso = ParticleSwarmOptimization { iterations: 10, ..pso() };
+        let first = pso.register(&source, &target, &initial_guess()).unwrap();
+        let second = pso.register(&source, &target, &initial_guess()).unwrap();
+        assert_eq!(first.transform, second.transform);
+        assert_eq!(first.history, second.history);
     }
 
-    // Return the best global transformation
-    if global_best_fitness < f32::INFINITY {
-        Ok(global_best_position)
-    } else {
-        Err("PSO failed to converge to a solution.".to_string())
+    #[test]
+    fn validates_the_swarm() {
+        assert!(ParticleSwarmOptimization::default().validate().is_ok());
+        assert!(ParticleSwarmOptimization { population_size: 0, ..Default::default() }.validate().is_err());
+        assert!(ParticleSwarmOptimization { max_velocity: Some(0.0), ..Default::default() }.validate().is_err());
     }
 }
@@ -0,0 +1,80 @@
+use rand::{thread_rng, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+/// Random number streams of a solver run, all derived from a single seed.
+///
+/// Work done inside rayon closures draws from the stream of its generation and index rather than
+/// from a thread-local generator, so the numbers drawn do not depend on which thread runs which
+/// individual, and a run can be replayed exactly from its seed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RandomStreams {
+    seed: u64,
+}
+
+impl RandomStreams {
+    /// Streams of the given seed, or of a random one when none is given
+    pub fn new(seed: Option<u64>) -> Self {
+        RandomStreams { seed: seed.unwrap_or_else(|| thread_rng().gen()) }
+    }
+
+    /// Seed to pass back to replay the run
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Stream of the sequential steps of the solver
+    pub fn main(&self) -> ChaCha8Rng {
+        self.derive(0, 0, 1)
+    }
+
+    /// Stream of one individual of one generation
+    pub fn stream(&self, generation: usize, index: usize) -> ChaCha8Rng {
+        self.derive(generation as u64, index as u64, 0)
+    }
+
+    // Every (generation, index, kind) gets its own ChaCha key
+    fn derive(&self, generation: u64, index: u64, kind: u8) -> ChaCha8Rng {
+        let mut key = [0u8; 32];
+        key[..8].copy_from_slice(&self.seed.to_le_bytes());
+        key[8..16].copy_from_slice(&generation.to_le_bytes());
+        key[16..24].copy_from_slice(&index.to_le_bytes());
+        key[24] = kind;
+        ChaCha8Rng::from_seed(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draw(mut rng: ChaCha8Rng) -> [u64; 4] {
+        [rng.gen(), rng.gen(), rng.gen(), rng.gen()]
+    }
+
+    #[test]
+    fn streams_are_reproducible_from_the_seed() {
+        let (a, b) = (RandomStreams::new(Some(42)), RandomStreams::new(Some(42)));
+        for generation in 0..3 {
+            for index in 0..3 {
+                assert_eq!(draw(a.stream(generation, index)), draw(b.stream(generation, index)));
+            }
+        }
+        assert_eq!(draw(a.main()), draw(b.main()));
+        assert_eq!(RandomStreams::new(Some(a.seed())), a);
+    }
+
+    #[test]
+    fn streams_differ_between_individuals() {
+        let streams = RandomStreams::new(Some(42));
+        let mut draws = vec![draw(streams.main())];
+        for generation in 0..3 {
+            for index in 0..3 {
+                draws.push(draw(streams.stream(generation, index)));
+            }
+        }
+        draws.push(draw(RandomStreams::new(Some(43)).stream(0, 0)));
+        for (i, a) in draws.iter().enumerate() {
+            assert!(draws[i + 1..].iter().all(|b| a != b));
+        }
+    }
+}
@@ -3,7 +3,7 @@ use bevy::{asset::RenderAssetUsages, prelude::*, render::mesh::PrimitiveTopology
 use nalgebra::Point3;
 use rand::Rng;
 
-use crate::{render::{self, CENTER}, utils::{self, compute_residual_error}};
+use crate::{dataset::FrameLabels, error::RegistrationError, render::{self, CENTER}, utils::{self, compute_residual_error}};
 use utils::{convert_vec, find_correspondences};
 
 #[derive(Resource)]
@@ -64,11 +64,16 @@ pub fn spawn_mesh<T: AsRef<Path>>(
     point_clouds: &mut ResMut<PointClouds>,
     color_path: T, 
     depth_path: T,
+    sample_step: u32,
     transform: Transform,
-    is_movable: bool
-) {
-    // Get mesh from RGBD image
-    let (positions, mesh) = render::rgbd_to_mesh(color_path, depth_path);
+    is_movable: bool,
+    labels: Option<&FrameLabels>
+) -> Result<(), RegistrationError> {
+    // Get mesh from RGBD image, colored by class if labels are given
+    let labeller = labels.map(|labels| move |points: &[[f32; 3]]| {
+        if is_movable { labels.source(points) } else { labels.target(points) }
+    });
+    let (positions, mesh) = render::rgbd_to_mesh(color_path, depth_path, sample_step, labeller)?;
 
     // Spawn the points mesh
     let mut entity = commands.spawn((
@@ -90,6 +95,8 @@ pub fn spawn_mesh<T: AsRef<Path>>(
         // Reference point cloud
         point_clouds.target = positions;
     }
+
+    Ok(())
 }
 
 /// Draws correspondences
@@ -1,6 +1,10 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
 use kiddo::{KdTree, SquaredEuclidean};
-use bevy::{math::Vec3, prelude::Transform};
-use nalgebra::Point3;
+use bevy::prelude::Transform;
+use nalgebra::{Matrix3, Point3, Vector3};
+use rayon::prelude::*;
+
+use crate::robust::{CorrespondenceOptions, Matcher};
 
 // Compute squared distance between two points
 fn distance_squared(p1: Point3<f32>, p2: Point3<f32>) -> f32 {
@@ -10,7 +14,7 @@ fn distance_squared(p1: Point3<f32>, p2: Point3<f32>) -> f32 {
     dx * dx + dy * dy + dz * dz
 }
 
-fn build_kd_tree(points: &Vec<Point3<f32>>) -> KdTree<f32, 3> {
+pub fn build_kd_tree(points: &[Point3<f32>]) -> KdTree<f32, 3> {
     let mut kdtree = KdTree::new();
     for (index, point) in points.iter().enumerate() {
         kdtree.add(&[point.x, point.y, point.z], index.try_into().unwrap());
@@ -18,6 +22,58 @@ fn build_kd_tree(points: &Vec<Point3<f32>>) -> KdTree<f32, 3> {
     kdtree
 }
 
+// Covariance of the k nearest neighbours of a point
+fn neighbourhood_covariance(
+    points: &[Point3<f32>],
+    kdtree: &KdTree<f32, 3>,
+    point: &Point3<f32>,
+    neighbours: usize
+) -> Matrix3<f32> {
+    let neighbourhood: Vec<Vector3<f32>> = kdtree
+        .nearest_n::<SquaredEuclidean>(&[point.x, point.y, point.z], neighbours)
+        .iter()
+        .map(|neighbour| points[neighbour.item as usize].coords)
+        .collect();
+
+    let centroid = neighbourhood.iter().sum::<Vector3<f32>>() / neighbourhood.len() as f32;
+    neighbourhood
+        .iter()
+        .map(|p| (p - centroid) * (p - centroid).transpose())
+        .sum::<Matrix3<f32>>() / neighbourhood.len() as f32
+}
+
+/// Estimates the normal of every point from its k nearest neighbours (smallest principal axis),
+/// oriented towards the camera, which is at the origin of the point cloud
+pub fn estimate_normals(points: &[Point3<f32>], kdtree: &KdTree<f32, 3>, neighbours: usize) -> Vec<Vector3<f32>> {
+    points
+        .par_iter()
+        .map(|point| {
+            let eigen = neighbourhood_covariance(points, kdtree, point, neighbours).symmetric_eigen();
+            let normal: Vector3<f32> = eigen.eigenvectors.column(eigen.eigenvalues.imin()).into();
+            if normal.dot(&point.coords) > 0.0 { -normal } else { normal }
+        })
+        .collect()
+}
+
+/// Estimates the covariance of every point from its k nearest neighbours, regularized as a
+/// plane: variance `epsilon` along the normal and 1 along the surface (Generalized-ICP)
+pub fn estimate_covariances(
+    points: &[Point3<f32>],
+    kdtree: &KdTree<f32, 3>,
+    neighbours: usize,
+    epsilon: f32
+) -> Vec<Matrix3<f32>> {
+    points
+        .par_iter()
+        .map(|point| {
+            let eigen = neighbourhood_covariance(points, kdtree, point, neighbours).symmetric_eigen();
+            let mut variances = Vector3::from_element(1.0);
+            variances[eigen.eigenvalues.imin()] = epsilon;
+            eigen.eigenvectors * Matrix3::from_diagonal(&variances) * eigen.eigenvectors.transpose()
+        })
+        .collect()
+}
+
 pub fn find_correspondences(
   source_points: &Vec<Point3<f32>>,
   target_points: &Vec<Point3<f32>>,
@@ -53,33 +109,39 @@ pub fn compute_residual_error(correspondences: &Vec<(Point3<f32>, Point3<f32>)>)
     residual_error
 }
 
-// Fitness function: Sum of closest point distances
-pub fn fitness(transform: &Transform, source_points: &Vec<Vec3>, target_points: &Vec<Vec3>) -> f32 {
-    /*source_points
-        .iter()
-        .map(|p| {
-            let transformed_point = transform.rotation * *p + transform.translation;
-            target_points
-                .iter()
-                .map(|t| transformed_point.distance(*t))
-                .fold(f32::INFINITY, f32::min)
-        })
-        .sum::<f32>() / target_points.len() as f32*/
-    let target = target_points.into_iter().map(
-        |t| Point3::from([t.x, t.y, t.z])
-    ).collect();
-    let transformed_source = source_points.into_iter().map(
-        |src| {
-            let src_point = Vec3 { x: src[0], y: src[1], z: src[2] };
-            let transformed_src = transform.transform_point(src_point);
-            let transformed_point = Point3::from([
-                transformed_src.x, transformed_src.y, transformed_src.z
-            ]);
-            transformed_point
+/// Fitness function: mean loss of the distances from the transformed source points to their closest
+/// target points, after rejecting the outliers. The target k-d tree is built once per run, and the
+/// context is shared by the threads that evaluate the individuals in parallel.
+pub struct FitnessContext {
+    matcher: Matcher,
+    evaluations: AtomicUsize,
+}
+
+impl FitnessContext {
+    /// Plain mean squared distance, every correspondence is kept
+    pub fn new(source: &[[f32; 3]], target: &[[f32; 3]]) -> Self {
+        Self::with_options(source, target, &CorrespondenceOptions::default())
+    }
+
+    pub fn with_options(source: &[[f32; 3]], target: &[[f32; 3]], options: &CorrespondenceOptions) -> Self {
+        FitnessContext {
+            matcher: Matcher::new(source, target, options),
+            evaluations: AtomicUsize::new(0),
         }
-    ).collect();
-    let correspondences = find_correspondences(
-        &transformed_source, &target
-    );
-    compute_residual_error(&correspondences)
-}
\ No newline at end of file
+    }
+
+    pub fn evaluate(&self, transform: &Transform) -> f32 {
+        self.evaluations.fetch_add(1, Ordering::Relaxed);
+        self.matcher.score(&self.matcher.find(transform))
+    }
+
+    /// Fraction of the source points with an accepted correspondence, not counted as an evaluation
+    pub fn overlap(&self, transform: &Transform) -> f32 {
+        self.matcher.overlap(&self.matcher.find(transform))
+    }
+
+    /// Number of times the fitness has been evaluated
+    pub fn evaluations(&self) -> usize {
+        self.evaluations.load(Ordering::Relaxed)
+    }
+}